@@ -1,5 +1,8 @@
+mod archive;
 mod queue;
+mod target;
 mod worker;
 
-pub use queue::{BackupQueue, BackupStatus, PendingBackup};
+pub use queue::{BackupQueue, BackupStatus, PendingBackup, QueueError};
+pub use target::{BackupError, BackupTarget, LocalCopyTarget, OnProgress, UploadProgress};
 pub use worker::spawn_worker;
@@ -0,0 +1,71 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+use zip::ZipWriter;
+use zip::write::SimpleFileOptions;
+
+/// Pending backups that share a date directory, uploaded together as one zip.
+#[derive(Debug)]
+pub struct PendingArchive {
+    pub date_dir: PathBuf,
+    pub files: Vec<PathBuf>,
+}
+
+impl PendingArchive {
+    /// Group pending backups by their date directory.
+    pub fn group(paths: Vec<PathBuf>) -> Vec<Self> {
+        let mut by_dir: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+        for path in paths {
+            let date_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+            by_dir.entry(date_dir).or_default().push(path);
+        }
+
+        by_dir
+            .into_iter()
+            .map(|(date_dir, files)| Self { date_dir, files })
+            .collect()
+    }
+
+    /// Zip the files into their date directory, returning the archive's path.
+    ///
+    /// The name is timestamped, since media from the same day can be downloaded by later
+    /// cleanups and must not replace an earlier archive.
+    pub async fn write_zip(&self) -> io::Result<PathBuf> {
+        let date = self
+            .date_dir
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let zip_path = self
+            .date_dir
+            .join(format!("{date}_{}.zip", Utc::now().timestamp()));
+
+        let files = self.files.clone();
+        let path = zip_path.clone();
+        tokio::task::spawn_blocking(move || write_zip(&path, &files))
+            .await
+            .map_err(io::Error::other)??;
+
+        Ok(zip_path)
+    }
+}
+
+fn write_zip(zip_path: &Path, files: &[PathBuf]) -> io::Result<()> {
+    let mut zip = ZipWriter::new(File::create(zip_path)?);
+    let options = SimpleFileOptions::default().large_file(true);
+
+    for path in files {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        zip.start_file(name, options).map_err(io::Error::other)?;
+        io::copy(&mut File::open(path)?, &mut zip)?;
+    }
+
+    zip.finish().map_err(io::Error::other)?;
+    Ok(())
+}
@@ -1,13 +1,20 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
 use chrono::{DateTime, Utc};
+use ring::digest::{SHA256, digest};
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{error, warn};
+
+use super::target::UploadProgress;
+use crate::config::{QueueLimit, QueueOverflowPolicy};
 
 const PENDING_BACKUPS_PATH: &str = "./pending_backups.toml";
-const PENDING_BACKUPS_TEMP_PATH: &str = "./pending_backups.toml.tmp";
 
 /// Status of a pending backup.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -15,7 +22,13 @@ const PENDING_BACKUPS_TEMP_PATH: &str = "./pending_backups.toml.tmp";
 pub enum BackupStatus {
     Pending,
     InProgress,
-    Failed { error: String },
+    Failed {
+        error: String,
+    },
+    /// Retries are exhausted, so the backup is no longer attempted.
+    DeadLettered {
+        error: String,
+    },
 }
 
 /// A backup that is pending cloud upload.
@@ -28,48 +41,242 @@ pub struct PendingBackup {
     pub timestamp: DateTime<Utc>,
     pub retry_count: u32,
     pub status: BackupStatus,
+    /// When a failed backup may next be retried.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_retry_at: Option<DateTime<Utc>>,
+    /// Account the upload went to, so a retry goes to the same one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    /// URL of the resumable upload session from an earlier attempt, so a retry can pick up where
+    /// it left off.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upload_session: Option<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum QueueError {
+    #[error("backup queue is full ({0} entries)")]
+    Full(usize),
+
+    #[error(transparent)]
+    Save(#[from] anyhow::Error),
 }
 
 /// Persistent queue for tracking pending backups.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BackupQueue {
+    /// Messages whose backups were evicted from a full queue. They're left in Discord rather than
+    /// downloaded and queued again, which would only evict something else, until the queue has
+    /// room again.
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    evicted_messages: HashSet<u64>,
     entries: HashMap<String, PendingBackup>,
+    #[serde(skip)]
+    limit: Option<QueueLimit>,
+    /// Where the queue is saved. The backup, checksum and temp files sit alongside it.
+    #[serde(skip)]
+    path: PathBuf,
+    /// Whether the queue file on disk is known to be intact, and so safe to keep as the backup.
+    #[serde(skip)]
+    file_intact: AtomicBool,
 }
 
 impl BackupQueue {
     /// Load the backup queue from disk, or create a new empty queue.
-    pub fn load() -> Result<Self> {
-        if let Ok(content) = fs::read_to_string(PENDING_BACKUPS_PATH) {
-            let mut queue: BackupQueue = toml::from_str(&content)
-                .context(format!("Failed to parse {}", PENDING_BACKUPS_PATH))?;
-
-            queue.entries.iter_mut().for_each(|(_, entry)| {
-                if entry.status == BackupStatus::InProgress {
-                    // If we're loading the list and it has InProgress items, that means the process
-                    // shut down during upload, reset status to pending
-                    entry.status = BackupStatus::Pending;
+    ///
+    /// A corrupt queue file is recovered from the copy saved before it. Only if that fails too
+    /// does this start with an empty queue, rather than keep the bot from starting.
+    pub fn load(limit: Option<QueueLimit>) -> Self {
+        Self::load_from(PathBuf::from(PENDING_BACKUPS_PATH), limit)
+    }
+
+    pub(crate) fn load_from(path: PathBuf, limit: Option<QueueLimit>) -> Self {
+        let mut queue = match Self::read(&path, Some(&sibling(&path, "sha256"))) {
+            Ok(queue) => {
+                if let Some(queue) = &queue {
+                    queue.file_intact.store(true, Ordering::Relaxed);
                 }
-            });
+                queue
+            }
+            Err(e) => {
+                error!(
+                    "Backup queue is corrupt, recovering from {}: {e:?}",
+                    sibling(&path, "bak").display()
+                );
+                Self::recover(&sibling(&path, "bak"))
+            }
+        }
+        .unwrap_or_else(|| Self {
+            evicted_messages: HashSet::new(),
+            entries: HashMap::new(),
+            limit: None,
+            path: PathBuf::new(),
+            file_intact: AtomicBool::new(false),
+        });
+        queue.limit = limit;
+        queue.path = path;
 
-            Ok(queue)
-        } else {
-            Ok(Self {
-                entries: HashMap::new(),
-            })
+        queue.entries.iter_mut().for_each(|(_, entry)| {
+            if entry.status == BackupStatus::InProgress {
+                // If we're loading the list and it has InProgress items, that means the process
+                // shut down during upload, reset status to pending
+                entry.status = BackupStatus::Pending;
+            }
+        });
+
+        queue
+    }
+
+    /// Read the copy of the queue saved before the corrupt one.
+    fn recover(bak_path: &Path) -> Option<Self> {
+        match Self::read(bak_path, None) {
+            Ok(Some(queue)) => {
+                warn!(
+                    "Recovered {} backups from {}, the latest changes to the queue may be lost",
+                    queue.entries.len(),
+                    bak_path.display()
+                );
+                Some(queue)
+            }
+            Ok(None) => {
+                error!(
+                    "No {} to recover from, starting with an empty queue",
+                    bak_path.display()
+                );
+                None
+            }
+            Err(e) => {
+                error!("Failed to recover backup queue, starting with an empty queue: {e:?}");
+                None
+            }
         }
     }
 
-    /// Add a backup to the queue.
-    pub fn add(&mut self, backup: PendingBackup) -> Result<()> {
-        let key = backup.local_path.to_string_lossy().to_string();
-        self.entries.insert(key, backup);
-        self.save()
+    /// Read a queue file, checking it against its checksum file if it has one. Returns `None` if
+    /// the file doesn't exist.
+    fn read(path: &Path, checksum_path: Option<&Path>) -> Result<Option<Self>> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context(format!("Failed to read {}", path.display())),
+        };
+
+        // Queues saved before checksums were written have no checksum file
+        if let Some(checksum_path) = checksum_path
+            && let Ok(expected) = fs::read_to_string(checksum_path)
+            && expected.trim() != checksum(&content)
+        {
+            bail!(
+                "{} doesn't match {}",
+                path.display(),
+                checksum_path.display()
+            );
+        }
+
+        let queue =
+            toml::from_str(&content).context(format!("Failed to parse {}", path.display()))?;
+        Ok(Some(queue))
+    }
+
+    /// Add all of one message's backups, applying the queue's overflow policy if it's full. None
+    /// are added if the queue can't make room for them all. Returns the backups evicted to make
+    /// room, whose local files the caller should delete.
+    pub fn add_message(
+        &mut self,
+        backups: Vec<PendingBackup>,
+    ) -> Result<Vec<PendingBackup>, QueueError> {
+        let Some(message_id) = backups.first().map(|b| b.message_id) else {
+            return Ok(Vec::new());
+        };
+        let new_entries = backups
+            .iter()
+            .filter(|b| !self.entries.contains_key(&*b.local_path.to_string_lossy()))
+            .count();
+
+        let mut evicted = Vec::new();
+        if let Some(limit) = self.limit
+            && new_entries > 0
+        {
+            while self.entries.len() + new_entries > limit.max_entries {
+                let dropped = match limit.overflow {
+                    QueueOverflowPolicy::Reject => Vec::new(),
+                    QueueOverflowPolicy::EvictOldest => self.evict_oldest(message_id),
+                };
+                if dropped.is_empty() {
+                    // Put back anything evicted for these backups, as they aren't being added
+                    // after all
+                    for backup in evicted {
+                        self.restore(backup);
+                    }
+                    return Err(QueueError::Full(self.entries.len()));
+                }
+                evicted.extend(dropped);
+            }
+        }
+
+        for backup in backups {
+            self.entries
+                .insert(backup.local_path.to_string_lossy().to_string(), backup);
+        }
+        self.save()?;
+        Ok(evicted)
+    }
+
+    /// Whether the message's backups were evicted from a full queue, so it should be left alone.
+    pub fn is_evicted(&self, message_id: u64) -> bool {
+        self.evicted_messages.contains(&message_id)
+    }
+
+    /// Undo the eviction of a backup.
+    fn restore(&mut self, backup: PendingBackup) {
+        self.evicted_messages.remove(&backup.message_id);
+        self.entries
+            .insert(backup.local_path.to_string_lossy().to_string(), backup);
+    }
+
+    /// Drop every backup of the oldest message (other than `keep_message_id`) whose backups are
+    /// all still pending, returning them. The message is remembered as evicted.
+    ///
+    /// A message is only dropped whole, as the worker deletes a message once none of its
+    /// backups are queued.
+    fn evict_oldest(&mut self, keep_message_id: u64) -> Vec<PendingBackup> {
+        let started: HashSet<u64> = self
+            .entries
+            .values()
+            .filter(|b| b.status != BackupStatus::Pending)
+            .map(|b| b.message_id)
+            .collect();
+
+        let Some(message_id) = self
+            .entries
+            .values()
+            .filter(|b| b.message_id != keep_message_id && !started.contains(&b.message_id))
+            .min_by_key(|b| b.timestamp)
+            .map(|b| b.message_id)
+        else {
+            return Vec::new();
+        };
+
+        warn!("Backup queue is full, evicting backups of message {message_id}");
+        self.evicted_messages.insert(message_id);
+        let (evicted, kept) = std::mem::take(&mut self.entries)
+            .into_iter()
+            .partition(|(_, b)| b.message_id == message_id);
+        self.entries = kept;
+        evicted.into_values().collect()
     }
 
     /// Remove a backup from the queue by its local path.
     pub fn remove(&mut self, local_path: &Path) -> Result<()> {
         let key = local_path.to_string_lossy().to_string();
         self.entries.remove(&key);
+        // Evicted messages can be queued again without evicting anything now
+        if self
+            .limit
+            .is_none_or(|limit| self.entries.len() < limit.max_entries)
+        {
+            self.evicted_messages.clear();
+        }
         self.save()
     }
 
@@ -81,16 +288,34 @@ impl BackupQueue {
             .collect()
     }
 
-    /// Get all failed backups that haven't exceeded max retries.
-    pub fn get_failed(&self, max_retries: u32) -> Vec<&PendingBackup> {
+    /// Get all failed backups that haven't exceeded max retries and are due a retry by `now`.
+    pub fn get_failed(&self, max_retries: u32, now: DateTime<Utc>) -> Vec<&PendingBackup> {
         self.entries
             .values()
             .filter(|b| {
-                matches!(b.status, BackupStatus::Failed { .. }) && b.retry_count < max_retries
+                matches!(b.status, BackupStatus::Failed { .. })
+                    && b.retry_count < max_retries
+                    && b.next_retry_at.is_none_or(|at| at <= now)
             })
             .collect()
     }
 
+    /// Returns how many backups are being uploaded.
+    pub fn in_progress_count(&self) -> usize {
+        self.entries
+            .values()
+            .filter(|b| b.status == BackupStatus::InProgress)
+            .count()
+    }
+
+    /// Get all backups whose retries are exhausted.
+    pub fn get_dead_lettered(&self) -> Vec<&PendingBackup> {
+        self.entries
+            .values()
+            .filter(|b| matches!(b.status, BackupStatus::DeadLettered { .. }))
+            .collect()
+    }
+
     /// Mark a backup as in progress.
     pub fn mark_in_progress(&mut self, local_path: &Path) -> Result<()> {
         let key = local_path.to_string_lossy().to_string();
@@ -101,40 +326,426 @@ impl BackupQueue {
         Ok(())
     }
 
-    /// Mark a backup as failed with an error message.
-    pub fn mark_failed(&mut self, local_path: &Path, error: String) -> Result<()> {
+    /// Mark a backup as failed with an error message, to be retried no sooner than `next_retry_at`.
+    /// Once it has failed `max_retries` times it is dead-lettered instead.
+    pub fn mark_failed(
+        &mut self,
+        local_path: &Path,
+        error: String,
+        next_retry_at: DateTime<Utc>,
+        max_retries: u32,
+    ) -> Result<()> {
         let key = local_path.to_string_lossy().to_string();
         if let Some(backup) = self.entries.get_mut(&key) {
-            backup.status = BackupStatus::Failed { error };
             backup.retry_count += 1;
+            if backup.retry_count >= max_retries {
+                backup.status = BackupStatus::DeadLettered { error };
+                backup.next_retry_at = None;
+            } else {
+                backup.status = BackupStatus::Failed { error };
+                backup.next_retry_at = Some(next_retry_at);
+            }
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Dead-letter any backup that has already used up its retries, e.g. one queued before
+    /// `max_retries` was lowered.
+    pub fn dead_letter_exhausted(&mut self, max_retries: u32) -> Result<()> {
+        let mut changed = false;
+        for backup in self.entries.values_mut() {
+            let error = match &backup.status {
+                BackupStatus::Failed { error } => error.clone(),
+                BackupStatus::Pending => "retries exhausted".to_string(),
+                BackupStatus::InProgress | BackupStatus::DeadLettered { .. } => continue,
+            };
+            if backup.retry_count >= max_retries {
+                backup.status = BackupStatus::DeadLettered { error };
+                backup.next_retry_at = None;
+                changed = true;
+            }
+        }
+
+        if changed {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Record how far a backup's upload got.
+    pub fn set_progress(&mut self, local_path: &Path, progress: &UploadProgress) -> Result<()> {
+        let key = local_path.to_string_lossy().to_string();
+        if let Some(backup) = self.entries.get_mut(&key) {
+            backup.account = progress.account.clone();
+            backup.upload_session = progress.upload_session.clone();
             self.save()?;
         }
         Ok(())
     }
 
+    /// Returns how far a backup's upload got on earlier attempts.
+    pub fn progress(&self, local_path: &Path) -> UploadProgress {
+        self.get(local_path)
+            .map(|b| UploadProgress {
+                account: b.account.clone(),
+                upload_session: b.upload_session.clone(),
+            })
+            .unwrap_or_default()
+    }
+
     /// Reset a failed backup to pending for retry.
     pub fn reset_to_pending(&mut self, local_path: &Path) -> Result<()> {
         let key = local_path.to_string_lossy().to_string();
         if let Some(backup) = self.entries.get_mut(&key) {
             backup.status = BackupStatus::Pending;
+            backup.next_retry_at = None;
             self.save()?;
         }
         Ok(())
     }
 
+    /// Whether any backup from the given Discord message is still queued.
+    pub fn has_message(&self, message_id: u64) -> bool {
+        self.entries.values().any(|b| b.message_id == message_id)
+    }
+
     /// Get a backup by its local path.
     pub fn get(&self, local_path: &Path) -> Option<&PendingBackup> {
         let key = local_path.to_string_lossy().to_string();
         self.entries.get(&key)
     }
 
-    /// Save the queue to disk atomically (write to temp file, then rename).
+    /// Write the queue to disk. Every change is already saved as it happens, so this is only a
+    /// last safeguard, e.g. at shutdown.
+    pub fn flush(&self) -> Result<()> {
+        self.save()
+    }
+
+    /// Save the queue to disk atomically (write to temp file, then rename), keeping the previous
+    /// save as a backup and writing a checksum alongside.
+    ///
+    /// The checksum is renamed into place first. A crash before the queue file follows leaves the
+    /// previous queue with a mismatched checksum, which `load` recovers from the backup, holding
+    /// the same queue.
     fn save(&self) -> Result<()> {
         let content = toml::to_string_pretty(&self)?;
-        let temp_path = PathBuf::from(PENDING_BACKUPS_TEMP_PATH);
+
+        // Only back up a queue file that's intact, so a corrupt one never replaces a good backup
+        if self.file_intact.load(Ordering::Relaxed) {
+            fs::copy(&self.path, sibling(&self.path, "bak"))
+                .context("Failed to back up backup queue file")?;
+        }
+
+        let temp_path = sibling(&self.path, "tmp");
+        let checksum_path = sibling(&self.path, "sha256");
+        let checksum_temp_path = sibling(&checksum_path, "tmp");
         fs::write(&temp_path, &content).context("Failed to write temp backup queue file")?;
-        fs::rename(&temp_path, PENDING_BACKUPS_PATH)
-            .context("Failed to rename backup queue file")?;
+        fs::write(&checksum_temp_path, checksum(&content))
+            .context("Failed to write temp backup queue checksum file")?;
+
+        // Until the queue file is renamed too, it doesn't match the checksum
+        self.file_intact.store(false, Ordering::Relaxed);
+        fs::rename(&checksum_temp_path, &checksum_path)
+            .context("Failed to rename backup queue checksum file")?;
+        fs::rename(&temp_path, &self.path).context("Failed to rename backup queue file")?;
+        self.file_intact.store(true, Ordering::Relaxed);
         Ok(())
     }
 }
+
+/// `path` with `.extension` appended, e.g. `queue.toml` to `queue.toml.bak`.
+fn sibling(path: &Path, extension: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(extension);
+    PathBuf::from(name)
+}
+
+/// Hex-encoded SHA-256 of the queue file's contents.
+fn checksum(content: &str) -> String {
+    digest(&SHA256, content.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn queue(
+        dir: &tempfile::TempDir,
+        max_entries: usize,
+        overflow: QueueOverflowPolicy,
+    ) -> BackupQueue {
+        BackupQueue::load_from(
+            dir.path().join("pending_backups.toml"),
+            Some(QueueLimit {
+                max_entries,
+                overflow,
+            }),
+        )
+    }
+
+    /// A pending backup of `file` from `message_id`, sent `minute` minutes into the day.
+    fn backup(message_id: u64, file: &str, minute: u32) -> PendingBackup {
+        PendingBackup {
+            message_id,
+            channel_id: 1,
+            local_path: PathBuf::from(file),
+            original_filename: file.to_string(),
+            timestamp: Utc.with_ymd_and_hms(2024, 1, 1, 0, minute, 0).unwrap(),
+            retry_count: 0,
+            status: BackupStatus::Pending,
+            next_retry_at: None,
+            account: None,
+            upload_session: None,
+        }
+    }
+
+    fn paths(backups: &[PendingBackup]) -> Vec<&Path> {
+        let mut paths: Vec<_> = backups.iter().map(|b| b.local_path.as_path()).collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn reject_refuses_backups_once_full() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut queue = queue(&dir, 2, QueueOverflowPolicy::Reject);
+        queue.add_message(vec![backup(1, "a", 0)]).unwrap();
+        queue.add_message(vec![backup(2, "b", 1)]).unwrap();
+
+        let result = queue.add_message(vec![backup(3, "c", 2)]);
+
+        assert!(matches!(result, Err(QueueError::Full(2))));
+        assert!(queue.has_message(1) && queue.has_message(2));
+        assert!(!queue.has_message(3));
+    }
+
+    #[test]
+    fn full_queue_still_updates_existing_backups() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut queue = queue(&dir, 1, QueueOverflowPolicy::Reject);
+        queue.add_message(vec![backup(1, "a", 0)]).unwrap();
+
+        assert!(
+            queue
+                .add_message(vec![backup(1, "a", 0)])
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn evict_oldest_drops_the_oldest_message() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut queue = queue(&dir, 2, QueueOverflowPolicy::EvictOldest);
+        queue.add_message(vec![backup(2, "b", 5)]).unwrap();
+        queue.add_message(vec![backup(1, "a", 0)]).unwrap();
+
+        let evicted = queue.add_message(vec![backup(3, "c", 10)]).unwrap();
+
+        assert_eq!(paths(&evicted), [Path::new("a")]);
+        assert!(!queue.has_message(1));
+        assert!(queue.is_evicted(1));
+        assert!(queue.has_message(2) && queue.has_message(3));
+    }
+
+    #[test]
+    fn evict_oldest_drops_every_backup_of_the_message() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut queue = queue(&dir, 3, QueueOverflowPolicy::EvictOldest);
+        queue.add_message(vec![backup(1, "a1", 0)]).unwrap();
+        queue.add_message(vec![backup(1, "a2", 0)]).unwrap();
+        queue.add_message(vec![backup(2, "b", 5)]).unwrap();
+
+        let evicted = queue.add_message(vec![backup(3, "c", 10)]).unwrap();
+
+        assert_eq!(paths(&evicted), [Path::new("a1"), Path::new("a2")]);
+        assert!(!queue.has_message(1));
+    }
+
+    #[test]
+    fn evict_oldest_skips_messages_being_uploaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut queue = queue(&dir, 2, QueueOverflowPolicy::EvictOldest);
+        queue.add_message(vec![backup(1, "a", 0)]).unwrap();
+        queue.add_message(vec![backup(2, "b", 5)]).unwrap();
+        queue.mark_in_progress(Path::new("a")).unwrap();
+
+        let evicted = queue.add_message(vec![backup(3, "c", 10)]).unwrap();
+
+        assert_eq!(paths(&evicted), [Path::new("b")]);
+        assert!(queue.has_message(1));
+    }
+
+    #[test]
+    fn evict_oldest_is_full_when_nothing_can_be_evicted() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut queue = queue(&dir, 2, QueueOverflowPolicy::EvictOldest);
+        queue.add_message(vec![backup(1, "a", 0)]).unwrap();
+        queue.add_message(vec![backup(2, "b", 5)]).unwrap();
+        queue.mark_in_progress(Path::new("a")).unwrap();
+        queue.mark_in_progress(Path::new("b")).unwrap();
+
+        let result = queue.add_message(vec![backup(3, "c", 10)]);
+
+        assert!(matches!(result, Err(QueueError::Full(2))));
+        assert!(queue.has_message(1) && queue.has_message(2));
+    }
+
+    #[test]
+    fn evict_oldest_never_evicts_the_message_being_added() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut queue = queue(&dir, 1, QueueOverflowPolicy::EvictOldest);
+        queue.add_message(vec![backup(1, "a1", 0)]).unwrap();
+
+        let result = queue.add_message(vec![backup(1, "a2", 0)]);
+
+        assert!(matches!(result, Err(QueueError::Full(1))));
+        assert!(!queue.is_evicted(1));
+    }
+
+    #[test]
+    fn failed_eviction_restores_what_it_evicted() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut queue = queue(&dir, 2, QueueOverflowPolicy::EvictOldest);
+        queue.add_message(vec![backup(1, "a", 0)]).unwrap();
+        queue.add_message(vec![backup(2, "b", 5)]).unwrap();
+        queue.mark_in_progress(Path::new("b")).unwrap();
+        // Shrink the queue so evicting message 1 isn't enough
+        queue.limit = Some(QueueLimit {
+            max_entries: 1,
+            overflow: QueueOverflowPolicy::EvictOldest,
+        });
+
+        let result = queue.add_message(vec![backup(3, "c", 10)]);
+
+        assert!(matches!(result, Err(QueueError::Full(2))));
+        assert!(queue.has_message(1));
+        assert!(!queue.is_evicted(1));
+    }
+
+    #[test]
+    fn add_message_evicts_enough_for_every_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut queue = queue(&dir, 3, QueueOverflowPolicy::EvictOldest);
+        queue.add_message(vec![backup(1, "a", 0)]).unwrap();
+        queue.add_message(vec![backup(2, "b", 5)]).unwrap();
+
+        let evicted = queue
+            .add_message(vec![backup(3, "c1", 10), backup(3, "c2", 10)])
+            .unwrap();
+
+        assert_eq!(paths(&evicted), [Path::new("a")]);
+        assert!(queue.has_message(2));
+        assert!(queue.get(Path::new("c1")).is_some() && queue.get(Path::new("c2")).is_some());
+    }
+
+    #[test]
+    fn add_message_adds_nothing_and_evicts_nothing_without_room_for_every_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut queue = queue(&dir, 2, QueueOverflowPolicy::EvictOldest);
+        queue.add_message(vec![backup(1, "a", 0)]).unwrap();
+        queue.add_message(vec![backup(2, "b", 5)]).unwrap();
+        queue.mark_in_progress(Path::new("b")).unwrap();
+
+        let result = queue.add_message(vec![backup(3, "c1", 10), backup(3, "c2", 10)]);
+
+        assert!(matches!(result, Err(QueueError::Full(2))));
+        assert!(queue.has_message(1));
+        assert!(!queue.is_evicted(1));
+        assert!(!queue.has_message(3));
+    }
+
+    #[test]
+    fn evicted_messages_are_saved() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut queue = queue(&dir, 1, QueueOverflowPolicy::EvictOldest);
+        queue.add_message(vec![backup(1, "a", 0)]).unwrap();
+        queue.add_message(vec![backup(2, "b", 5)]).unwrap();
+
+        let reloaded = BackupQueue::load_from(dir.path().join("pending_backups.toml"), None);
+
+        assert!(reloaded.is_evicted(1));
+        assert!(reloaded.has_message(2));
+    }
+
+    #[test]
+    fn evicted_messages_are_queued_again_once_there_is_room() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut queue = queue(&dir, 1, QueueOverflowPolicy::EvictOldest);
+        queue.add_message(vec![backup(1, "a", 0)]).unwrap();
+        queue.add_message(vec![backup(2, "b", 5)]).unwrap();
+        assert!(queue.is_evicted(1));
+
+        queue.remove(Path::new("b")).unwrap();
+
+        assert!(!queue.is_evicted(1));
+        assert!(
+            queue
+                .add_message(vec![backup(1, "a", 0)])
+                .unwrap()
+                .is_empty()
+        );
+        assert!(queue.has_message(1));
+    }
+
+    #[test]
+    fn mark_failed_dead_letters_at_max_retries() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut queue = queue(&dir, 10, QueueOverflowPolicy::Reject);
+        queue.add_message(vec![backup(1, "a", 0)]).unwrap();
+        let now = Utc::now();
+
+        queue
+            .mark_failed(Path::new("a"), "first".to_string(), now, 2)
+            .unwrap();
+        assert!(matches!(
+            queue.get(Path::new("a")).unwrap().status,
+            BackupStatus::Failed { .. }
+        ));
+        assert!(queue.get_dead_lettered().is_empty());
+
+        queue
+            .mark_failed(Path::new("a"), "second".to_string(), now, 2)
+            .unwrap();
+        let backup = queue.get(Path::new("a")).unwrap();
+        assert_eq!(
+            backup.status,
+            BackupStatus::DeadLettered {
+                error: "second".to_string()
+            }
+        );
+        assert_eq!(backup.next_retry_at, None);
+        assert_eq!(queue.get_dead_lettered().len(), 1);
+    }
+
+    #[test]
+    fn get_failed_skips_dead_lettered_backups() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut queue = queue(&dir, 10, QueueOverflowPolicy::Reject);
+        queue.add_message(vec![backup(1, "a", 0)]).unwrap();
+        queue.add_message(vec![backup(2, "b", 1)]).unwrap();
+        let now = Utc::now();
+
+        queue
+            .mark_failed(Path::new("a"), "retry".to_string(), now, 2)
+            .unwrap();
+        queue
+            .mark_failed(Path::new("b"), "give up".to_string(), now, 1)
+            .unwrap();
+
+        let failed: Vec<_> = queue
+            .get_failed(2, now)
+            .into_iter()
+            .map(|b| b.local_path.as_path())
+            .collect();
+        assert_eq!(failed, [Path::new("a")]);
+    }
+}
@@ -0,0 +1,78 @@
+use std::path::{Path, PathBuf};
+
+use serenity::async_trait;
+use thiserror::Error;
+use tracing::debug;
+
+use crate::onedrive::OneDriveError;
+
+#[derive(Error, Debug)]
+pub enum BackupError {
+    #[error(transparent)]
+    OneDrive(#[from] OneDriveError),
+
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// How far an earlier attempt at an upload got, kept in the backup queue between attempts.
+#[derive(Debug, Clone, Default)]
+pub struct UploadProgress {
+    /// The account the upload went to, for targets with several.
+    pub account: Option<String>,
+    /// URL of the resumable upload session.
+    pub upload_session: Option<String>,
+}
+
+/// Called whenever an upload's progress changes, so it can be saved.
+pub type OnProgress<'a> = dyn Fn(&UploadProgress) + Sync + 'a;
+
+/// Somewhere downloaded media is backed up to.
+#[async_trait]
+pub trait BackupTarget: Send + Sync {
+    /// Upload a file to the target.
+    ///
+    /// `progress` is where an earlier attempt left off, and `on_progress` is called as it changes
+    /// so it can be saved. Targets without resumable uploads ignore both.
+    async fn upload_file(
+        &self,
+        path: &Path,
+        progress: &UploadProgress,
+        on_progress: &OnProgress<'_>,
+    ) -> Result<(), BackupError>;
+}
+
+/// Copies files into a directory, keeping their date folder.
+pub struct LocalCopyTarget {
+    directory: PathBuf,
+}
+
+impl LocalCopyTarget {
+    pub fn new(directory: PathBuf) -> Self {
+        Self { directory }
+    }
+}
+
+#[async_trait]
+impl BackupTarget for LocalCopyTarget {
+    async fn upload_file(
+        &self,
+        path: &Path,
+        _progress: &UploadProgress,
+        _on_progress: &OnProgress<'_>,
+    ) -> Result<(), BackupError> {
+        let mut destination = self.directory.clone();
+        if let Some(date_dir) = path.parent().and_then(|p| p.file_name()) {
+            destination.push(date_dir);
+        }
+        tokio::fs::create_dir_all(&destination).await?;
+
+        if let Some(file_name) = path.file_name() {
+            destination.push(file_name);
+        }
+        tokio::fs::copy(path, &destination).await?;
+
+        debug!("Copied {} to {}", path.display(), destination.display());
+        Ok(())
+    }
+}
@@ -1,43 +1,58 @@
-use std::ops::Deref;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+use chrono::Utc;
+use futures::stream::{self, StreamExt};
+use serenity::all::{ChannelId, Http, HttpError, MessageId};
 use tokio::task::JoinHandle;
 use tokio::time::interval;
 use tracing::{debug, error, info, warn};
 
-use super::queue::BackupQueue;
+use super::archive::PendingArchive;
+use super::queue::{BackupQueue, BackupStatus};
+use super::target::{BackupTarget, OnProgress, UploadProgress};
+use crate::cancellation::CancellationToken;
 use crate::config::BackupWorkerConfig;
-use crate::onedrive::OneDriveClient;
 
 /// Spawn the background backup worker.
 pub fn spawn_worker(
+    http: Arc<Http>,
     queue: Arc<Mutex<BackupQueue>>,
     config: BackupWorkerConfig,
-    onedrive_client: Arc<OneDriveClient>,
+    archive_daily: bool,
+    target: Arc<dyn BackupTarget>,
+    shutdown: CancellationToken,
 ) -> JoinHandle<()> {
     tokio::spawn(async move {
-        run_worker(queue, config, onedrive_client).await;
+        run_worker(http, queue, config, archive_daily, target, shutdown).await;
     })
 }
 
 async fn run_worker(
+    http: Arc<Http>,
     queue: Arc<Mutex<BackupQueue>>,
     config: BackupWorkerConfig,
-    onedrive_client: Arc<OneDriveClient>,
+    archive_daily: bool,
+    target: Arc<dyn BackupTarget>,
+    shutdown: CancellationToken,
 ) {
     let check_interval = Duration::from_secs(config.check_interval_seconds);
     let mut interval = interval(check_interval);
 
     info!(
-        "Backup worker started (check interval: {}s, max retries: {})",
-        config.check_interval_seconds, config.max_retries
+        "Backup worker started (check interval: {}s, max retries: {}, concurrent uploads: {})",
+        config.check_interval_seconds, config.max_retries, config.max_concurrent_uploads
     );
 
     loop {
         interval.tick().await;
 
+        if shutdown.is_cancelled() {
+            info!("Backup worker stopped");
+            return;
+        }
+
         let pending: Vec<_> = {
             let queue = queue.lock().unwrap();
             queue
@@ -54,108 +69,284 @@ async fn run_worker(
 
         info!("Processing {} pending backups", pending.len());
 
-        for local_path in pending {
-            // Check if file still exists
+        let concurrency = config.max_concurrent_uploads.max(1);
+        if archive_daily {
+            stream::iter(PendingArchive::group(pending))
+                .for_each_concurrent(concurrency, |archive| {
+                    process_archive(&http, &queue, &config, target.as_ref(), &shutdown, archive)
+                })
+                .await;
+        } else {
+            stream::iter(pending)
+                .for_each_concurrent(concurrency, |local_path| {
+                    process_backup(
+                        &http,
+                        &queue,
+                        &config,
+                        target.as_ref(),
+                        &shutdown,
+                        local_path,
+                    )
+                })
+                .await;
+        }
+
+        // Reset failed backups to pending for retry
+        reset_failed_for_retry(&queue, &config);
+    }
+}
+
+/// Upload a single pending backup, then clean up after it or mark it failed.
+async fn process_backup(
+    http: &Http,
+    queue: &Mutex<BackupQueue>,
+    config: &BackupWorkerConfig,
+    target: &dyn BackupTarget,
+    shutdown: &CancellationToken,
+    local_path: PathBuf,
+) {
+    // Leave it queued for after a restart
+    if shutdown.is_cancelled() {
+        return;
+    }
+
+    // Check if file still exists
+    if !local_path.exists() {
+        warn!("Backup file missing: {}", local_path.display());
+        let mut queue = queue.lock().unwrap();
+        mark_failed(&mut queue, &local_path, "file missing".to_string(), config);
+        return;
+    }
+
+    // Get backup info
+    let (retry_count, progress) = {
+        let queue = queue.lock().unwrap();
+        if let Some(backup) = queue.get(&local_path) {
+            (backup.retry_count, queue.progress(&local_path))
+        } else {
+            return;
+        }
+    };
+
+    // Mark as in progress
+    {
+        let mut queue = queue.lock().unwrap();
+        if let Err(e) = queue.mark_in_progress(&local_path) {
+            error!("Failed to mark backup as in progress: {e:?}");
+            return;
+        }
+    }
+
+    // Attempt upload
+    let save_progress = |progress: &UploadProgress| {
+        let mut queue = queue.lock().unwrap();
+        if let Err(e) = queue.set_progress(&local_path, progress) {
+            warn!("Failed to save upload progress: {e:?}");
+        }
+    };
+
+    match upload_to_target(&local_path, &progress, &save_progress, target).await {
+        Ok(()) => {
+            info!("Successfully uploaded {}", local_path.display());
+
+            complete_backup(http, queue, &local_path).await;
+        }
+        Err(e) => {
+            warn!(
+                "Failed to upload {} (attempt {}): {e}",
+                local_path.display(),
+                retry_count + 1
+            );
+
+            // Mark as failed (will be retried once its backoff has passed)
+            let mut queue = queue.lock().unwrap();
+            mark_failed(&mut queue, &local_path, e.to_string(), config);
+        }
+    }
+}
+
+/// Clean up after a backup is safely uploaded: drop it from the queue, delete its Discord
+/// message once all of the message's media is uploaded, and delete the local file.
+async fn complete_backup(http: &Http, queue: &Mutex<BackupQueue>, local_path: &Path) {
+    // Remove from queue, noting whether that was the message's last file
+    let uploaded_message = {
+        let mut queue = queue.lock().unwrap();
+        let ids = queue.get(local_path).map(|b| (b.channel_id, b.message_id));
+        if let Err(e) = queue.remove(local_path) {
+            error!("Failed to remove backup from queue: {e:?}");
+        }
+        ids.filter(|&(_, message_id)| !queue.has_message(message_id))
+    };
+
+    // All of the message's media is safely uploaded, so the message can go
+    if let Some((channel_id, message_id)) = uploaded_message {
+        delete_message(http, ChannelId::new(channel_id), MessageId::new(message_id)).await;
+    }
+
+    // Delete local file
+    if let Err(e) = tokio::fs::remove_file(local_path).await {
+        error!(
+            "Failed to delete local file {}: {e:?}",
+            local_path.display()
+        );
+    } else {
+        debug!("Deleted local file {}", local_path.display());
+
+        // remove_dir only removes empty directories — safe to call unconditionally
+        if let Some(parent) = local_path.parent() {
+            let _ = tokio::fs::remove_dir(parent).await;
+        }
+    }
+}
+
+/// Zip a day's pending backups and upload the archive, then clean up after every file in it or
+/// mark them all failed. The originals are only deleted once the archive is uploaded.
+async fn process_archive(
+    http: &Http,
+    queue: &Mutex<BackupQueue>,
+    config: &BackupWorkerConfig,
+    target: &dyn BackupTarget,
+    shutdown: &CancellationToken,
+    archive: PendingArchive,
+) {
+    // Leave it queued for after a restart
+    if shutdown.is_cancelled() {
+        return;
+    }
+
+    let mut files = Vec::with_capacity(archive.files.len());
+    {
+        let mut queue = queue.lock().unwrap();
+        for local_path in archive.files {
             if !local_path.exists() {
                 warn!("Backup file missing: {}", local_path.display());
-                let mut queue = queue.lock().unwrap();
-                if let Err(e) = queue.mark_failed(&local_path, "file missing".to_string()) {
-                    error!("Failed to mark backup as failed: {e:?}");
-                }
-                continue;
+                mark_failed(&mut queue, &local_path, "file missing".to_string(), config);
+            } else if let Err(e) = queue.mark_in_progress(&local_path) {
+                error!("Failed to mark backup as in progress: {e:?}");
+            } else {
+                files.push(local_path);
             }
+        }
+    }
 
-            // Get backup info and check retry count
-            let (retry_count, should_skip) = {
-                let queue = queue.lock().unwrap();
-                if let Some(backup) = queue.get(&local_path) {
-                    (backup.retry_count, backup.retry_count >= config.max_retries)
-                } else {
-                    continue;
-                }
-            };
-
-            if should_skip {
-                debug!(
-                    "Skipping {} - max retries ({}) exceeded",
-                    local_path.display(),
-                    config.max_retries
-                );
-                continue;
+    if files.is_empty() {
+        return;
+    }
+
+    let archive = PendingArchive {
+        date_dir: archive.date_dir,
+        files,
+    };
+    let result = match archive.write_zip().await {
+        Ok(zip_path) => {
+            // A fresh zip is built on each attempt, so there's no earlier upload to resume
+            let result =
+                upload_to_target(&zip_path, &UploadProgress::default(), &|_| {}, target).await;
+            if let Err(e) = tokio::fs::remove_file(&zip_path).await {
+                error!("Failed to delete archive {}: {e:?}", zip_path.display());
             }
+            result
+        }
+        Err(e) => Err(format!("failed to create archive: {e}")),
+    };
 
-            // Mark as in progress
-            {
-                let mut queue = queue.lock().unwrap();
-                if let Err(e) = queue.mark_in_progress(&local_path) {
-                    error!("Failed to mark backup as in progress: {e:?}");
-                    continue;
-                }
+    match result {
+        Ok(()) => {
+            info!(
+                "Successfully uploaded {} files from {}",
+                archive.files.len(),
+                archive.date_dir.display()
+            );
+            for local_path in &archive.files {
+                complete_backup(http, queue, local_path).await;
             }
+        }
+        Err(e) => {
+            warn!(
+                "Failed to upload archive of {}: {e}",
+                archive.date_dir.display()
+            );
 
-            // Attempt upload
-            match upload_to_cloud(&local_path, onedrive_client.deref()).await {
-                Ok(()) => {
-                    info!("Successfully uploaded {}", local_path.display());
-
-                    // Remove from queue
-                    {
-                        let mut queue = queue.lock().unwrap();
-                        if let Err(e) = queue.remove(&local_path) {
-                            error!("Failed to remove backup from queue: {e:?}");
-                        }
-                    }
-
-                    // Delete local file
-                    if let Err(e) = tokio::fs::remove_file(&local_path).await {
-                        error!(
-                            "Failed to delete local file {}: {e:?}",
-                            local_path.display()
-                        );
-                    } else {
-                        debug!("Deleted local file {}", local_path.display());
-
-                        // remove_dir only removes empty directories — safe to call unconditionally
-                        if let Some(parent) = local_path.parent() {
-                            let _ = tokio::fs::remove_dir(parent).await;
-                        }
-                    }
-                }
-                Err(e) => {
-                    warn!(
-                        "Failed to upload {} (attempt {}): {e}",
-                        local_path.display(),
-                        retry_count + 1
-                    );
-
-                    // Mark as failed (will be retried on next cycle after delay)
-                    let mut queue = queue.lock().unwrap();
-                    if let Err(e) = queue.mark_failed(&local_path, e.to_string()) {
-                        error!("Failed to mark backup as failed: {e:?}");
-                    }
-                }
+            let mut queue = queue.lock().unwrap();
+            for local_path in &archive.files {
+                mark_failed(&mut queue, local_path, e.clone(), config);
             }
         }
+    }
+}
 
-        // Reset failed backups to pending for retry
-        reset_failed_for_retry(&queue, &config);
+/// Delete a Discord message whose media has been backed up.
+async fn delete_message(http: &Http, channel_id: ChannelId, message_id: MessageId) {
+    match channel_id.delete_message(http, message_id).await {
+        Ok(()) => info!("Deleted message {message_id} after successful backup"),
+        // Someone got there first, which is fine
+        Err(serenity::Error::Http(HttpError::UnsuccessfulRequest(response)))
+            if response.status_code.as_u16() == 404 =>
+        {
+            debug!("Message {message_id} was already deleted");
+        }
+        Err(e) => error!("Failed to delete message {message_id} after backup: {e:?}"),
     }
 }
 
-/// Upload file to cloud storage.
-async fn upload_to_cloud(local_path: &Path, client: &OneDriveClient) -> Result<(), String> {
-    client
-        .upload_file(local_path)
+/// Upload file to the backup target, reporting its progress so a later attempt can resume it.
+async fn upload_to_target(
+    local_path: &Path,
+    progress: &UploadProgress,
+    on_progress: &OnProgress<'_>,
+    target: &dyn BackupTarget,
+) -> Result<(), String> {
+    target
+        .upload_file(local_path, progress, on_progress)
         .await
         .map_err(|e| e.to_string())
 }
 
-/// Reset failed backups to pending status for retry.
+/// Mark a backup as failed, backing off exponentially (with jitter) before its next retry.
+fn mark_failed(
+    queue: &mut BackupQueue,
+    local_path: &Path,
+    error: String,
+    config: &BackupWorkerConfig,
+) {
+    let retry_count = queue.get(local_path).map_or(0, |b| b.retry_count);
+    let delay = retry_delay(config, retry_count);
+    debug!("Retrying {} in {delay:?}", local_path.display());
+
+    let next_retry_at = Utc::now() + delay;
+    if let Err(e) = queue.mark_failed(local_path, error, next_retry_at, config.max_retries) {
+        error!("Failed to mark backup as failed: {e:?}");
+    } else if let Some(backup) = queue.get(local_path)
+        && matches!(backup.status, BackupStatus::DeadLettered { .. })
+    {
+        warn!(
+            "Giving up on {} after {} attempts",
+            local_path.display(),
+            backup.retry_count
+        );
+    }
+}
+
+/// How long to wait before retrying after `retry_count` earlier failures: the base delay doubled
+/// per failure, capped, plus up to a quarter more of jitter so failures don't retry in lockstep.
+fn retry_delay(config: &BackupWorkerConfig, retry_count: u32) -> Duration {
+    let backoff = config
+        .retry_base_seconds
+        .saturating_mul(2u64.saturating_pow(retry_count))
+        .min(config.retry_max_seconds);
+    Duration::from_secs(backoff).mul_f64(1.0 + fastrand::f64() / 4.0)
+}
+
+/// Reset failed backups whose backoff has passed to pending status for retry.
 fn reset_failed_for_retry(queue: &Arc<Mutex<BackupQueue>>, config: &BackupWorkerConfig) {
     let failed_paths: Vec<_> = {
-        let queue = queue.lock().unwrap();
+        let mut queue = queue.lock().unwrap();
+        if let Err(e) = queue.dead_letter_exhausted(config.max_retries) {
+            error!("Failed to dead-letter exhausted backups: {e:?}");
+        }
+
         queue
-            .get_failed(config.max_retries)
+            .get_failed(config.max_retries, Utc::now())
             .into_iter()
             .map(|b| b.local_path.clone())
             .collect()
@@ -170,3 +361,88 @@ fn reset_failed_for_retry(queue: &Arc<Mutex<BackupQueue>>, config: &BackupWorker
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use serenity::async_trait;
+    use tokio::time::sleep;
+
+    use super::*;
+    use crate::backup::{BackupError, PendingBackup};
+    use crate::cancellation::CancellationRegistry;
+
+    /// Fails every upload after a while, recording how many ran at once.
+    #[derive(Default)]
+    struct ConcurrencyProbe {
+        running: AtomicUsize,
+        max_running: AtomicUsize,
+        uploads: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl BackupTarget for ConcurrencyProbe {
+        async fn upload_file(
+            &self,
+            _path: &Path,
+            _progress: &UploadProgress,
+            _on_progress: &OnProgress<'_>,
+        ) -> Result<(), BackupError> {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_running.fetch_max(running, Ordering::SeqCst);
+            sleep(Duration::from_millis(100)).await;
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            self.uploads.fetch_add(1, Ordering::SeqCst);
+            // Failing keeps the worker from deleting the Discord message
+            Err(BackupError::Io(std::io::Error::other("upload failed")))
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn uploads_run_up_to_the_concurrency_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut queue = BackupQueue::load_from(dir.path().join("pending_backups.toml"), None);
+        for message_id in 1..=6 {
+            let local_path = dir.path().join(format!("{message_id}.jpg"));
+            std::fs::write(&local_path, "media").unwrap();
+            queue
+                .add_message(vec![PendingBackup {
+                    message_id,
+                    channel_id: 1,
+                    local_path,
+                    original_filename: format!("{message_id}.jpg"),
+                    timestamp: Utc::now(),
+                    retry_count: 0,
+                    status: BackupStatus::Pending,
+                    next_retry_at: None,
+                    account: None,
+                    upload_session: None,
+                }])
+                .unwrap();
+        }
+
+        let config = BackupWorkerConfig {
+            max_concurrent_uploads: 2,
+            ..BackupWorkerConfig::default()
+        };
+        let target = Arc::new(ConcurrencyProbe::default());
+        let mut cancellation = CancellationRegistry::new();
+        let worker = spawn_worker(
+            Arc::new(Http::new("")),
+            Arc::new(Mutex::new(queue)),
+            config,
+            false,
+            Arc::clone(&target) as Arc<dyn BackupTarget>,
+            cancellation.shutdown_token(),
+        );
+
+        // Long enough for every upload, but before the next check
+        sleep(Duration::from_secs(1)).await;
+        cancellation.cancel_all();
+        worker.await.unwrap();
+
+        assert_eq!(target.uploads.load(Ordering::SeqCst), 6);
+        assert_eq!(target.max_running.load(Ordering::SeqCst), 2);
+    }
+}
@@ -15,22 +15,26 @@ impl CancellationToken {
 }
 
 /// Registry for per-channel cancellation tokens.
-/// Allows cleanup tasks to be cancelled when a channel is disabled.
+/// Allows cleanup tasks to be cancelled when a channel is disabled, or all work to be cancelled
+/// when the bot shuts down.
 pub struct CancellationRegistry {
     tokens: HashMap<ChannelId, watch::Sender<bool>>,
+    shutdown: watch::Sender<bool>,
 }
 
 impl CancellationRegistry {
     pub fn new() -> Self {
         Self {
             tokens: HashMap::new(),
+            shutdown: watch::channel(false).0,
         }
     }
 
     /// Register a new cancellation token for a channel.
     /// Returns a token that the cleanup task can check for cancellation.
+    /// Once shutting down, the token starts out cancelled.
     pub fn register(&mut self, channel_id: ChannelId) -> CancellationToken {
-        let (tx, rx) = watch::channel(false);
+        let (tx, rx) = watch::channel(*self.shutdown.borrow());
         self.tokens.insert(channel_id, tx);
         CancellationToken(rx)
     }
@@ -47,6 +51,24 @@ impl CancellationRegistry {
         }
     }
 
+    /// Returns a token that's cancelled when the bot shuts down.
+    pub fn shutdown_token(&self) -> CancellationToken {
+        CancellationToken(self.shutdown.subscribe())
+    }
+
+    /// Signal cancellation for every channel's cleanup task and for the shutdown token.
+    pub fn cancel_all(&mut self) {
+        self.shutdown.send_replace(true);
+        for tx in self.tokens.values() {
+            let _ = tx.send(true);
+        }
+    }
+
+    /// Returns how many cleanup tasks are running.
+    pub fn running_count(&self) -> usize {
+        self.tokens.len()
+    }
+
     /// Remove a channel's cancellation token.
     pub fn deregister(&mut self, channel_id: ChannelId) {
         self.tokens.remove(&channel_id);
@@ -1,3 +1,4 @@
+pub mod member_cache;
 pub mod queue;
 pub mod task;
 pub mod worker;
@@ -0,0 +1,46 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use serenity::all::{GuildId, RoleId, UserId};
+
+/// How long a member's roles are trusted before they're fetched again.
+const MEMBER_TTL: Duration = Duration::from_secs(15 * 60);
+
+/// Guild members' roles, kept between cleanup runs so message authors aren't refetched every
+/// time.
+#[derive(Debug, Default)]
+pub struct MemberCache {
+    guilds: HashMap<GuildId, HashMap<UserId, CachedMember>>,
+}
+
+#[derive(Debug)]
+struct CachedMember {
+    roles: Vec<RoleId>,
+    fetched_at: Instant,
+}
+
+impl MemberCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a user's roles in the guild, or `None` if they aren't cached or have expired.
+    pub fn roles(&self, guild_id: GuildId, user_id: UserId) -> Option<&[RoleId]> {
+        self.guilds
+            .get(&guild_id)?
+            .get(&user_id)
+            .filter(|member| member.fetched_at.elapsed() < MEMBER_TTL)
+            .map(|member| member.roles.as_slice())
+    }
+
+    /// Cache a user's roles in the guild. Users who aren't members are cached with no roles.
+    pub fn insert(&mut self, guild_id: GuildId, user_id: UserId, roles: Vec<RoleId>) {
+        self.guilds.entry(guild_id).or_default().insert(
+            user_id,
+            CachedMember {
+                roles,
+                fetched_at: Instant::now(),
+            },
+        );
+    }
+}
@@ -1,7 +1,9 @@
+use std::collections::HashSet;
 use std::num::NonZeroU32;
 
-use serenity::all::{Message, MessageId};
+use serenity::all::{Message, MessageId, UserId};
 
+use crate::config::MediaBackupConfig;
 use crate::media::{AttachmentsExt, MediaAttachment};
 
 /// A message that should be deleted immediately (no media backup needed).
@@ -36,12 +38,27 @@ impl ClassifiedMessages {
     }
 }
 
-/// Classify messages into delete jobs (no media) and backup jobs (has media).
-pub fn classify_messages(messages: Vec<Message>) -> ClassifiedMessages {
+/// Classify messages into delete jobs (no media to back up) and backup jobs (has media to back
+/// up), skipping messages by preserved authors. Only media passing `media_backup`'s extension
+/// allowlist and size cap is backed up; the rest goes with its message.
+pub fn classify_messages(
+    messages: Vec<Message>,
+    preserved_authors: &HashSet<UserId>,
+    media_backup: &MediaBackupConfig,
+) -> ClassifiedMessages {
     let mut result = ClassifiedMessages::new();
 
     for message in messages {
-        let media_attachments = message.attachments.extract_media();
+        if preserved_authors.contains(&message.author.id) {
+            continue;
+        }
+
+        let media_attachments: Vec<_> = message
+            .attachments
+            .extract_media()
+            .into_iter()
+            .filter(|attachment| media_backup.should_back_up(attachment))
+            .collect();
 
         if media_attachments.is_empty() {
             result.delete_jobs.push(DeleteJob {
@@ -59,12 +76,110 @@ pub fn classify_messages(messages: Vec<Message>) -> ClassifiedMessages {
     result
 }
 
-/// Filter messages to only those older than the retention cutoff.
-pub fn filter_expired_messages(messages: Vec<Message>, retention_days: NonZeroU32) -> Vec<Message> {
-    let cutoff = chrono::Utc::now() - chrono::Duration::days(retention_days.get() as i64);
+/// Filter messages to only those older than the retention cutoff, and never younger than
+/// `min_age_days`.
+pub fn filter_expired_messages(
+    messages: Vec<Message>,
+    retention_days: NonZeroU32,
+    min_age_days: Option<NonZeroU32>,
+) -> Vec<Message> {
+    let days = min_age_days.map_or(retention_days, |floor| retention_days.max(floor));
+    let cutoff = chrono::Utc::now() - chrono::Duration::days(days.get() as i64);
 
     messages
         .into_iter()
         .filter(|m| *m.timestamp < cutoff)
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use serenity::all::Attachment;
+
+    use super::*;
+
+    fn attachment(filename: &str, content_type: &str, size: u32) -> Attachment {
+        serde_json::from_value(serde_json::json!({
+            "id": "1",
+            "filename": filename,
+            "proxy_url": "",
+            "size": size,
+            "url": format!("https://cdn.example/{filename}"),
+            "content_type": content_type,
+        }))
+        .unwrap()
+    }
+
+    fn message(id: u64, author: u64, attachments: Vec<Attachment>) -> Message {
+        let mut message = Message::default();
+        message.id = MessageId::new(id);
+        message.author.id = UserId::new(author);
+        message.attachments = attachments;
+        message
+    }
+
+    #[test]
+    fn preserved_authors_messages_are_left_alone() {
+        let preserved = HashSet::from([UserId::new(1)]);
+        let messages = vec![
+            message(10, 1, Vec::new()),
+            message(11, 1, vec![attachment("photo.jpg", "image/jpeg", 100)]),
+            message(12, 2, Vec::new()),
+            message(13, 2, vec![attachment("photo.jpg", "image/jpeg", 100)]),
+        ];
+
+        let classified = classify_messages(messages, &preserved, &MediaBackupConfig::default());
+
+        let deleted: Vec<_> = classified
+            .delete_jobs
+            .iter()
+            .map(|j| j.message_id)
+            .collect();
+        let backed_up: Vec<_> = classified
+            .backup_jobs
+            .iter()
+            .map(|j| j.message_id)
+            .collect();
+        assert_eq!(deleted, [MessageId::new(12)]);
+        assert_eq!(backed_up, [MessageId::new(13)]);
+    }
+
+    #[test]
+    fn messages_with_only_filtered_media_are_deleted() {
+        let media_backup = MediaBackupConfig {
+            extensions: vec!["jpg".to_string()],
+            max_attachment_bytes: Some(1000),
+            ..MediaBackupConfig::default()
+        };
+        let messages = vec![
+            // Disallowed extension
+            message(10, 2, vec![attachment("clip.mp4", "video/mp4", 100)]),
+            // Over the size cap
+            message(11, 2, vec![attachment("photo.jpg", "image/jpeg", 1001)]),
+            // Keeps only the allowed attachment
+            message(
+                12,
+                2,
+                vec![
+                    attachment("clip.mp4", "video/mp4", 100),
+                    attachment("photo.jpg", "image/jpeg", 1000),
+                ],
+            ),
+        ];
+
+        let classified = classify_messages(messages, &HashSet::new(), &media_backup);
+
+        let deleted: Vec<_> = classified
+            .delete_jobs
+            .iter()
+            .map(|j| j.message_id)
+            .collect();
+        assert_eq!(deleted, [MessageId::new(10), MessageId::new(11)]);
+        let [backup] = classified.backup_jobs.as_slice() else {
+            panic!("expected one backup job: {:?}", classified.backup_jobs);
+        };
+        assert_eq!(backup.message_id, MessageId::new(12));
+        let filenames: Vec<_> = backup.attachments.iter().map(|a| &a.filename).collect();
+        assert_eq!(filenames, ["photo.jpg"]);
+    }
+}
@@ -1,18 +1,25 @@
+use std::collections::HashSet;
 use std::num::NonZeroU32;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use anyhow::{Context, Result};
 use chrono::Days;
-use serenity::all::{ChannelId, GetMessages, Http, Timestamp};
+use metrics_client::MetricsClient;
+use serenity::all::{
+    ChannelId, GetMessages, GuildId, Http, HttpError, Mentionable, Message, RoleId, StatusCode,
+    Timestamp, UserId,
+};
 use tokio::time::sleep;
 use tracing::{debug, error, info, warn};
 
-use crate::backup::{BackupQueue, BackupStatus, PendingBackup};
+use crate::backup::{BackupQueue, BackupStatus, PendingBackup, QueueError};
 use crate::cancellation::{CancellationRegistry, CancellationToken};
+use crate::cleanup::member_cache::MemberCache;
 use crate::cleanup::queue::{BackupJob, DeleteJob, classify_messages, filter_expired_messages};
-use crate::config::ConfigStore;
-use crate::media::MediaDownloader;
+use crate::config::{ConfigStore, MediaBackupConfig};
+use crate::media::{DownloadResult, MediaDownloader, dir_size};
+use crate::metrics::{Event, label, value};
 
 // Note: Discord requires messages to be < 14 days old for bulk delete
 // see (https://discord.com/developers/docs/resources/message#bulk-delete-messages).
@@ -21,26 +28,59 @@ const BULK_DELETE_MIN: usize = 2;
 const BULK_DELETE_MAX: usize = 100;
 const SINGLE_DELETE_DELAY: Duration = Duration::from_millis(200);
 const BULK_DELETE_DELAY: Duration = Duration::from_secs(1);
+const MAX_RATE_LIMIT_RETRIES: u32 = 3;
 const MAX_MESSAGES_PER_FETCH: u8 = 100;
 const TARGET_EXPIRED_MESSAGES: usize = 100;
 const MAX_PAGINATION_ROUNDS: usize = 10;
+const MAX_ARCHIVED_THREADS_PER_FETCH: u64 = 100;
+/// Above this many uncached authors, page through the guild's members instead of fetching each
+/// author on their own.
+const BATCH_MEMBER_FETCH_THRESHOLD: usize = 10;
+const MAX_MEMBERS_PER_FETCH: u64 = 1000;
+
+/// What a completed cleanup pass did in a channel.
+#[derive(Debug, Default)]
+struct CleanupReport {
+    /// Messages deleted, or that would have been in a dry run.
+    deleted: usize,
+    /// Media files queued for backup, or that would have been in a dry run.
+    backed_up: usize,
+    pagination_rounds: usize,
+}
+
+impl CleanupReport {
+    fn add(&mut self, other: &CleanupReport) {
+        self.deleted += other.deleted;
+        self.backed_up += other.backed_up;
+        self.pagination_rounds += other.pagination_rounds;
+    }
+}
 
 /// Run cleanup for a single channel.
+#[allow(clippy::too_many_arguments)]
 pub async fn cleanup_channel(
     http: Arc<Http>,
     config: ConfigStore,
     backup_queue: Arc<Mutex<BackupQueue>>,
+    member_cache: Arc<Mutex<MemberCache>>,
     cancellation: Arc<Mutex<CancellationRegistry>>,
+    metrics: Option<MetricsClient<Event>>,
     channel_id: ChannelId,
     retention_days: NonZeroU32,
+    uploads_enabled: bool,
     cancel_token: CancellationToken,
 ) {
+    let dry_run = config.dry_run();
+    let audit_channel_id = config.audit_channel_id();
     let result = run_cleanup(
         http,
         config,
         backup_queue,
+        &member_cache,
         channel_id,
         retention_days,
+        audit_channel_id,
+        uploads_enabled,
         cancel_token,
     )
     .await;
@@ -48,37 +88,145 @@ pub async fn cleanup_channel(
     // Deregister cancellation token
     cancellation.lock().unwrap().deregister(channel_id);
 
-    if let Err(e) = result {
-        error!("Cleanup failed for channel {channel_id}: {e:?}");
+    match result {
+        Ok(Some(report)) if !dry_run => {
+            if let Some(metrics) = &metrics {
+                metrics
+                    .event(Event::ChannelCleaned)
+                    .label(label::CHANNEL_ID, &channel_id.to_string())
+                    .value(value::MESSAGES_DELETED, report.deleted as f64)
+                    .value(value::MEDIA_BACKED_UP, report.backed_up as f64)
+                    .value(value::PAGINATION_ROUNDS, report.pagination_rounds as f64)
+                    .record();
+            }
+        }
+        Ok(_) => {}
+        Err(e) => error!("Cleanup failed for channel {channel_id}: {e:?}"),
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn run_cleanup(
     http: Arc<Http>,
     config: ConfigStore,
     backup_queue: Arc<Mutex<BackupQueue>>,
+    member_cache: &Mutex<MemberCache>,
     channel_id: ChannelId,
     retention_days: NonZeroU32,
+    audit_channel_id: Option<ChannelId>,
+    uploads_enabled: bool,
     cancel_token: CancellationToken,
-) -> Result<()> {
-    use serenity::all::{Message, MessageId};
+) -> Result<Option<CleanupReport>> {
+    let dry_run = config.dry_run();
+    if dry_run {
+        info!("Starting dry run for channel {channel_id} (retention: {retention_days} days)");
+    } else {
+        info!("Starting cleanup for channel {channel_id} (retention: {retention_days} days)");
+    }
+
+    let Some(mut report) = clean_messages(
+        &http,
+        &config,
+        &backup_queue,
+        member_cache,
+        channel_id,
+        None,
+        retention_days,
+        uploads_enabled,
+        &cancel_token,
+    )
+    .await?
+    else {
+        return Ok(None);
+    };
+
+    // The channel itself was cleaned, so still report it if its threads can't be listed
+    let thread_ids = threads_to_clean(&http, &config, channel_id)
+        .await
+        .unwrap_or_else(|e| {
+            warn!("Failed to list threads of channel {channel_id}, skipping them: {e:?}");
+            Vec::new()
+        });
+    for thread_id in thread_ids {
+        match clean_messages(
+            &http,
+            &config,
+            &backup_queue,
+            member_cache,
+            channel_id,
+            Some(thread_id),
+            retention_days,
+            uploads_enabled,
+            &cancel_token,
+        )
+        .await
+        {
+            Ok(Some(thread_report)) => report.add(&thread_report),
+            Ok(None) => return Ok(None),
+            // One thread failing shouldn't stop the rest of the channel's threads
+            Err(e) => warn!("Cleanup failed for thread {thread_id} in channel {channel_id}: {e:?}"),
+        }
+    }
+
+    if dry_run {
+        info!(
+            "Dry run completed for channel {channel_id}: would delete {} messages and back up {} media files",
+            report.deleted, report.backed_up
+        );
+    } else {
+        info!(
+            "Cleanup completed for channel {channel_id}: deleted {} messages, queued {} media files for backup",
+            report.deleted, report.backed_up
+        );
+
+        if let Some(audit_channel_id) = audit_channel_id {
+            post_audit_report(&http, audit_channel_id, channel_id, retention_days, &report).await;
+        }
+    }
+
+    Ok(Some(report))
+}
+
+/// Clean up expired messages in a channel, or in one of its threads if `thread_id` is given,
+/// picking up from where the last run left off.
+#[allow(clippy::too_many_arguments)]
+async fn clean_messages(
+    http: &Http,
+    config: &ConfigStore,
+    backup_queue: &Mutex<BackupQueue>,
+    member_cache: &Mutex<MemberCache>,
+    channel_id: ChannelId,
+    thread_id: Option<ChannelId>,
+    retention_days: NonZeroU32,
+    uploads_enabled: bool,
+    cancel_token: &CancellationToken,
+) -> Result<Option<CleanupReport>> {
+    use serenity::all::MessageId;
 
-    info!("Starting cleanup for channel {channel_id} (retention: {retention_days} days)");
+    let dry_run = config.dry_run();
+    let target = thread_id.unwrap_or(channel_id);
+    let min_age_days = config.min_age_days(channel_id);
 
     // Load pagination cursor from config
-    let mut cursor: Option<MessageId> =
-        config.get_pagination_cursor(channel_id).map(MessageId::new);
+    let mut cursor: Option<MessageId> = match thread_id {
+        Some(thread_id) => config.get_thread_cursor(channel_id, thread_id),
+        None => config.get_pagination_cursor(channel_id),
+    }
+    .map(MessageId::new);
 
     let mut expired_messages: Vec<Message> = Vec::new();
     let mut reached_end = false;
+    let mut report = CleanupReport::default();
 
     // Pagination loop
     for round in 0..MAX_PAGINATION_ROUNDS {
         if cancel_token.is_cancelled() {
-            info!("Cleanup cancelled for channel {channel_id}");
-            return Ok(());
+            info!("Cleanup cancelled for channel {target}");
+            return Ok(None);
         }
 
+        report.pagination_rounds = round + 1;
+
         // Build request with pagination
         let request = match cursor {
             Some(before_id) => GetMessages::new()
@@ -94,21 +242,18 @@ async fn run_cleanup(
         );
 
         // Fetch messages
-        let messages = channel_id
-            .messages(&http, request)
+        let messages = target
+            .messages(http, request)
             .await
             .context("Failed to fetch messages")?;
 
         if messages.is_empty() {
-            debug!("No more messages in channel {channel_id}");
+            debug!("No more messages in channel {target}");
             reached_end = true;
             break;
         }
 
-        debug!(
-            "Fetched {} messages from channel {channel_id}",
-            messages.len()
-        );
+        debug!("Fetched {} messages from channel {target}", messages.len());
 
         // Update cursor to oldest message in batch (last element, since messages are newest-first)
         if let Some(oldest) = messages.last() {
@@ -122,7 +267,7 @@ async fn run_cleanup(
         }
 
         // Filter expired messages and add to collection
-        let batch_expired = filter_expired_messages(messages, retention_days);
+        let batch_expired = filter_expired_messages(messages, retention_days, min_age_days);
         debug!("Found {} expired messages in batch", batch_expired.len());
         expired_messages.extend(batch_expired);
 
@@ -148,15 +293,26 @@ async fn run_cleanup(
     }
 
     if expired_messages.is_empty() {
-        info!("No expired messages in channel {channel_id}");
+        info!("No expired messages in channel {target}");
     } else {
         info!(
-            "Found {} expired messages in channel {channel_id}",
+            "Found {} expired messages in channel {target}",
             expired_messages.len()
         );
 
-        // Classify into delete vs backup jobs
-        let classified = classify_messages(expired_messages);
+        // Classify into delete vs backup jobs, leaving preserved authors' messages alone
+        let (preserve_user_ids, preserve_role_ids) = config.preserved(channel_id);
+        let preserved = preserved_authors(
+            http,
+            member_cache,
+            target,
+            &expired_messages,
+            &preserve_user_ids,
+            &preserve_role_ids,
+        )
+        .await?;
+        let media_backup = config.media_backup_config();
+        let classified = classify_messages(expired_messages, &preserved, &media_backup);
         info!(
             "Classified: {} delete jobs, {} backup jobs",
             classified.delete_jobs.len(),
@@ -164,125 +320,470 @@ async fn run_cleanup(
         );
 
         if cancel_token.is_cancelled() {
-            info!("Cleanup cancelled for channel {channel_id}");
-            return Ok(());
+            info!("Cleanup cancelled for channel {target}");
+            return Ok(None);
         }
 
         // Process delete jobs (non-media messages)
         if !classified.delete_jobs.is_empty() {
-            delete_messages(&http, channel_id, &classified.delete_jobs, &cancel_token).await?;
+            report.deleted =
+                delete_messages(http, target, &classified.delete_jobs, dry_run, cancel_token)
+                    .await?;
         }
 
         if cancel_token.is_cancelled() {
-            info!("Cleanup cancelled for channel {channel_id}");
-            return Ok(());
+            info!("Cleanup cancelled for channel {target}");
+            return Ok(None);
         }
 
         // Process backup jobs (media messages)
         if !classified.backup_jobs.is_empty() {
-            let download_dir = config.media_backup_config().download_dir;
-
-            process_backup_jobs(
-                &http,
-                channel_id,
-                download_dir,
-                &backup_queue,
+            report.backed_up = process_backup_jobs(
+                http,
+                target,
+                &media_backup,
+                uploads_enabled,
+                dry_run,
+                backup_queue,
                 &classified.backup_jobs,
-                &cancel_token,
+                cancel_token,
             )
             .await?;
         }
     }
 
-    if reached_end {
+    let cursor = if reached_end {
         debug!("Reached end of channel history, clearing pagination cursor");
-        config.set_pagination_cursor(channel_id, None)?;
+        None
     } else {
         debug!("Saving pagination cursor: {:?}", cursor);
-        config.set_pagination_cursor(channel_id, cursor.map(|c| c.get()))?;
+        cursor.map(|c| c.get())
+    };
+    match thread_id {
+        Some(thread_id) => config.set_thread_cursor(channel_id, thread_id, cursor)?,
+        None => config.set_pagination_cursor(channel_id, cursor)?,
+    }
+
+    Ok(Some(report))
+}
+
+/// Find the threads and forum posts in a channel to clean up along with it.
+async fn threads_to_clean(
+    http: &Http,
+    config: &ConfigStore,
+    channel_id: ChannelId,
+) -> Result<Vec<ChannelId>> {
+    let (include_threads, include_archived_threads) = config.thread_options(channel_id);
+    if !include_threads {
+        return Ok(Vec::new());
+    }
+
+    let Some(guild_id) = channel_id
+        .to_channel(http)
+        .await
+        .context("Failed to fetch channel")?
+        .guild()
+        .map(|c| c.guild_id)
+    else {
+        return Ok(Vec::new());
+    };
+
+    // Discord only lists active threads for the whole guild
+    let mut thread_ids: Vec<ChannelId> = guild_id
+        .get_active_threads(http)
+        .await
+        .context("Failed to fetch active threads")?
+        .threads
+        .into_iter()
+        .filter(|thread| thread.parent_id == Some(channel_id))
+        .map(|thread| thread.id)
+        .collect();
+
+    if include_archived_threads {
+        let archived = channel_id
+            .get_archived_public_threads(http, None, Some(MAX_ARCHIVED_THREADS_PER_FETCH))
+            .await
+            .context("Failed to fetch archived threads")?;
+        if archived.has_more {
+            debug!(
+                "Channel {channel_id} has more than {MAX_ARCHIVED_THREADS_PER_FETCH} archived threads, only cleaning up the most recent"
+            );
+        }
+        thread_ids.extend(archived.threads.into_iter().map(|thread| thread.id));
+    }
+
+    thread_ids.sort_unstable();
+    thread_ids.dedup();
+    config.retain_thread_cursors(channel_id, &thread_ids)?;
+
+    debug!(
+        "Cleaning up {} threads in channel {channel_id}",
+        thread_ids.len()
+    );
+    Ok(thread_ids)
+}
+
+/// Post a short report of a channel's cleanup to the audit channel, unless nothing happened.
+async fn post_audit_report(
+    http: &Http,
+    audit_channel_id: ChannelId,
+    channel_id: ChannelId,
+    retention_days: NonZeroU32,
+    report: &CleanupReport,
+) {
+    if report.deleted == 0 && report.backed_up == 0 {
+        return;
+    }
+
+    // The name keeps the report readable even after the channel is deleted
+    let name = channel_id
+        .name(http)
+        .await
+        .unwrap_or_else(|_| channel_id.to_string());
+    let message = format!(
+        "**#{name}** ({channel}, {retention_days} day retention): Deleted {} messages, backed up {} media files",
+        report.deleted,
+        report.backed_up,
+        channel = channel_id.mention(),
+    );
+
+    if let Err(e) = audit_channel_id.say(http, message).await {
+        warn!("Failed to post cleanup report to audit channel {audit_channel_id}: {e:?}");
+    }
+}
+
+/// Find which of the messages' authors are preserved, either by id or by holding a preserved role.
+async fn preserved_authors(
+    http: &Http,
+    member_cache: &Mutex<MemberCache>,
+    channel_id: ChannelId,
+    messages: &[Message],
+    user_ids: &[UserId],
+    role_ids: &[RoleId],
+) -> Result<HashSet<UserId>> {
+    let mut preserved: HashSet<UserId> = messages
+        .iter()
+        .map(|m| m.author.id)
+        .filter(|id| user_ids.contains(id))
+        .collect();
+
+    if role_ids.is_empty() {
+        return Ok(preserved);
+    }
+
+    // Fetched messages don't carry member roles, so look the authors up in the guild
+    let Some(guild_id) = channel_id
+        .to_channel(http)
+        .await
+        .context("Failed to fetch channel")?
+        .guild()
+        .map(|c| c.guild_id)
+    else {
+        return Ok(preserved);
+    };
+
+    let authors: HashSet<UserId> = messages
+        .iter()
+        .map(|m| m.author.id)
+        .filter(|id| !preserved.contains(id))
+        .collect();
+    let uncached: Vec<UserId> = {
+        let member_cache = member_cache.lock().unwrap();
+        authors
+            .iter()
+            .copied()
+            .filter(|&id| member_cache.roles(guild_id, id).is_none())
+            .collect()
+    };
+
+    let batch_fetched = uncached.len() > BATCH_MEMBER_FETCH_THRESHOLD
+        && match fetch_all_members(http, member_cache, guild_id, &uncached).await {
+            Ok(()) => true,
+            // Listing members needs the privileged Server Members intent
+            Err(e) => {
+                warn!(
+                    "Failed to list members of guild {guild_id}, fetching authors one by one: {e:?}"
+                );
+                false
+            }
+        };
+    if !batch_fetched {
+        fetch_members(http, member_cache, guild_id, &uncached).await?;
+    }
+
+    {
+        let member_cache = member_cache.lock().unwrap();
+        preserved.extend(authors.into_iter().filter(|&author| {
+            member_cache
+                .roles(guild_id, author)
+                .is_some_and(|roles| roles.iter().any(|r| role_ids.contains(r)))
+        }));
+    }
+
+    debug!(
+        "Preserving messages from {} authors in channel {channel_id}",
+        preserved.len()
+    );
+
+    Ok(preserved)
+}
+
+/// Fetch each of `authors` from the guild into the member cache.
+async fn fetch_members(
+    http: &Http,
+    member_cache: &Mutex<MemberCache>,
+    guild_id: GuildId,
+    authors: &[UserId],
+) -> Result<()> {
+    for &author in authors {
+        let roles = match guild_id.member(http, author).await {
+            Ok(member) => member.roles,
+            // Webhooks and users who left the server have no roles
+            Err(serenity::Error::Http(HttpError::UnsuccessfulRequest(response)))
+                if response.status_code == StatusCode::NOT_FOUND =>
+            {
+                Vec::new()
+            }
+            Err(e) => return Err(e).context("Failed to fetch message author"),
+        };
+        member_cache.lock().unwrap().insert(guild_id, author, roles);
+    }
+
+    Ok(())
+}
+
+/// Page through a guild's members, caching all of them, until every one of `authors` is found.
+/// Authors who aren't members are cached with no roles.
+async fn fetch_all_members(
+    http: &Http,
+    member_cache: &Mutex<MemberCache>,
+    guild_id: GuildId,
+    authors: &[UserId],
+) -> Result<()> {
+    let mut remaining: HashSet<UserId> = authors.iter().copied().collect();
+    let mut after = None;
+
+    while !remaining.is_empty() {
+        let members = guild_id
+            .members(http, Some(MAX_MEMBERS_PER_FETCH), after)
+            .await
+            .context("Failed to fetch guild members")?;
+        let last_page = (members.len() as u64) < MAX_MEMBERS_PER_FETCH;
+        after = members.last().map(|m| m.user.id);
+
+        let mut member_cache = member_cache.lock().unwrap();
+        for member in members {
+            remaining.remove(&member.user.id);
+            member_cache.insert(guild_id, member.user.id, member.roles);
+        }
+
+        if last_page {
+            break;
+        }
     }
 
-    info!("Cleanup completed for channel {channel_id}");
+    // Webhooks and users who left the server have no roles
+    let mut member_cache = member_cache.lock().unwrap();
+    for author in remaining {
+        member_cache.insert(guild_id, author, Vec::new());
+    }
 
     Ok(())
 }
 
+/// Splits `jobs` into those to bulk delete and those to delete one at a time.
+/// Only messages newer than `bulk_delete_cutoff` can be bulk deleted, and only
+/// when there are enough of them.
+fn partition_deletes(
+    jobs: &[DeleteJob],
+    bulk_delete_cutoff: Timestamp,
+) -> (Vec<&DeleteJob>, Vec<&DeleteJob>) {
+    let (mut bulk_jobs, mut individual_jobs): (Vec<_>, Vec<_>) = jobs
+        .iter()
+        .partition(|j| j.message_id.created_at() > bulk_delete_cutoff);
+
+    if bulk_jobs.len() < BULK_DELETE_MIN {
+        individual_jobs.append(&mut bulk_jobs);
+    }
+
+    (bulk_jobs, individual_jobs)
+}
+
 /// Delete non-media messages with rate limiting.
+///
+/// Returns how many messages were deleted, or would have been in a dry run.
 async fn delete_messages(
     http: &Http,
     channel_id: ChannelId,
     jobs: &[DeleteJob],
+    dry_run: bool,
     cancel_token: &CancellationToken,
-) -> Result<()> {
+) -> Result<usize> {
     let bulk_delete_cutoff: Timestamp = Timestamp::now()
         .checked_sub_days(BULK_DELETE_THRESHOLD)
         .context("can't compute bulk delete cutoff")?
         .into();
-    let (mut bulk_jobs, mut individual_jobs): (Vec<_>, Vec<_>) = jobs.iter().partition(|j|
-        // We can bulk delete messages newer than the cutoff
-        j.message_id.created_at() > bulk_delete_cutoff);
+    let (bulk_jobs, individual_jobs) = partition_deletes(jobs, bulk_delete_cutoff);
 
-    if bulk_jobs.len() < BULK_DELETE_MIN {
-        individual_jobs.append(&mut bulk_jobs);
+    if dry_run {
+        let ids: Vec<_> = bulk_jobs.iter().map(|j| j.message_id).collect();
+        if !ids.is_empty() {
+            info!("Would bulk delete messages from channel {channel_id}: {ids:?}");
+        }
+        for job in &individual_jobs {
+            info!("Would delete message {}", job.message_id);
+        }
+        return Ok(bulk_jobs.len() + individual_jobs.len());
     }
 
+    let mut deleted = 0;
+
     if !bulk_jobs.is_empty() {
         let chunks: Vec<_> = bulk_jobs.chunks(BULK_DELETE_MAX).collect();
 
         for chunk in chunks {
             if cancel_token.is_cancelled() {
-                return Ok(());
+                return Ok(deleted);
             }
 
-            if let Err(e) = channel_id
-                .delete_messages(http, chunk.iter().map(|f| f.message_id))
-                .await
-            {
+            let result = with_rate_limit_retry(BULK_DELETE_DELAY, || {
+                channel_id.delete_messages(http, chunk.iter().map(|f| f.message_id))
+            })
+            .await;
+
+            if let Err(e) = result {
                 warn!("Bulk delete failed: {e:?}",);
             } else {
                 info!(
                     "Bulk deleted {} messages from channel {channel_id}",
                     chunk.len(),
                 );
+                deleted += chunk.len();
             }
-
-            sleep(BULK_DELETE_DELAY).await;
         }
     }
 
     if !individual_jobs.is_empty() {
-        for job in jobs {
+        for job in &individual_jobs {
             if cancel_token.is_cancelled() {
-                return Ok(());
+                return Ok(deleted);
             }
 
-            if let Err(e) = channel_id.delete_message(http, job.message_id).await {
+            let result = with_rate_limit_retry(SINGLE_DELETE_DELAY, || {
+                channel_id.delete_message(http, job.message_id)
+            })
+            .await;
+
+            if let Err(e) = result {
                 error!("Failed to delete message {}: {e:?}", job.message_id);
             } else {
                 debug!("Deleted message {}", job.message_id);
+                deleted += 1;
             }
-
-            sleep(SINGLE_DELETE_DELAY).await;
         }
     }
 
-    Ok(())
+    Ok(deleted)
 }
 
-/// Process backup jobs: download media locally, add to backup queue, then delete Discord message.
+/// Send a Discord request, retrying it up to [`MAX_RATE_LIMIT_RETRIES`] times if it's rate limited.
+///
+/// Serenity's ratelimiter already keeps requests within each route's budget and waits out the
+/// `retry-after` of any 429 before resending. A 429 only reaches us when Discord didn't say how
+/// long to wait, so those retries wait `fallback_delay` instead.
+async fn with_rate_limit_retry<T, F, Fut>(
+    fallback_delay: Duration,
+    mut request: F,
+) -> serenity::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = serenity::Result<T>>,
+{
+    let mut retries = 0;
+    loop {
+        match request().await {
+            Err(serenity::Error::Http(HttpError::UnsuccessfulRequest(response)))
+                if response.status_code == StatusCode::TOO_MANY_REQUESTS
+                    && retries < MAX_RATE_LIMIT_RETRIES =>
+            {
+                retries += 1;
+                warn!(
+                    "Rate limited, retrying in {fallback_delay:?} ({retries}/{MAX_RATE_LIMIT_RETRIES})"
+                );
+                sleep(fallback_delay).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Process backup jobs: download media locally and add it to the backup queue.
+///
+/// When uploads are enabled, the backup worker deletes each Discord message once all its files
+/// are uploaded. Otherwise the local copy is the backup, so the message is deleted right away.
+///
+/// Returns how many media files were queued, or would have been in a dry run.
+#[allow(clippy::too_many_arguments)]
 async fn process_backup_jobs(
     http: &Http,
     channel_id: ChannelId,
-    download_dir: std::path::PathBuf,
+    media_backup: &MediaBackupConfig,
+    uploads_enabled: bool,
+    dry_run: bool,
     backup_queue: &Mutex<BackupQueue>,
     jobs: &[BackupJob],
     cancel_token: &CancellationToken,
-) -> Result<()> {
-    let downloader = MediaDownloader::new(download_dir);
+) -> Result<usize> {
+    let downloader = MediaDownloader::new(media_backup.download_dir.clone());
+    let mut backed_up = 0;
 
     for job in jobs {
         if cancel_token.is_cancelled() {
-            return Ok(());
+            return Ok(backed_up);
+        }
+
+        // Messages stay in Discord until their upload finishes, so later runs see them again
+        {
+            let queue = backup_queue.lock().unwrap();
+            if queue.has_message(job.message_id.get()) {
+                debug!(
+                    "Media for message {} is already queued for backup",
+                    job.message_id
+                );
+                continue;
+            }
+            if queue.is_evicted(job.message_id.get()) {
+                debug!(
+                    "Skipping message {}, its backups were evicted from the full backup queue",
+                    job.message_id
+                );
+                continue;
+            }
+        }
+
+        if dry_run {
+            info!(
+                "Would back up {} attachments from message {} and then delete it",
+                job.attachments.len(),
+                job.message_id
+            );
+            backed_up += job.attachments.len();
+            continue;
+        }
+
+        // Leave the message for a later run if its media would overfill the download directory
+        if let Some(max_bytes) = media_backup.max_download_dir_bytes {
+            let used = dir_size(&media_backup.download_dir).with_context(|| {
+                format!("Failed to measure {}", media_backup.download_dir.display())
+            })?;
+            let needed: u64 = job.attachments.iter().map(|a| a.size).sum();
+            if used + needed > max_bytes {
+                warn!(
+                    "Deferring backup of message {}: {needed} bytes would exceed the download directory quota ({used}/{max_bytes} bytes used)",
+                    job.message_id
+                );
+                continue;
+            }
         }
 
         info!(
@@ -313,27 +814,67 @@ async fn process_backup_jobs(
             }
         };
 
-        {
-            let mut queue = backup_queue.lock().unwrap();
+        let pending = pending_backups(channel_id, job, &results);
+        // All of the message's files are queued or none are, or the worker would delete the
+        // message once the queued ones are uploaded
+        let mut all_queued = true;
+        let mut queue_full = false;
+        let evicted = match backup_queue.lock().unwrap().add_message(pending) {
+            Ok(evicted) => evicted,
+            Err(QueueError::Full(entries)) => {
+                warn!(
+                    "Backup queue is full ({entries} entries), leaving message {} for a later run",
+                    job.message_id
+                );
+                queue_full = true;
+                Vec::new()
+            }
+            Err(e) => {
+                error!(
+                    "Failed to add backups to queue for message {}: {e:?}",
+                    job.message_id
+                );
+                all_queued = false;
+                Vec::new()
+            }
+        };
+
+        // Evicted backups will never be uploaded, so their files would only take up space
+        for backup in &evicted {
+            if let Err(e) = tokio::fs::remove_file(&backup.local_path).await {
+                error!(
+                    "Failed to delete evicted file {}: {e:?}",
+                    backup.local_path.display()
+                );
+            } else if let Some(parent) = backup.local_path.parent() {
+                // remove_dir only removes empty directories
+                let _ = tokio::fs::remove_dir(parent).await;
+            }
+        }
+
+        if queue_full {
             for result in &results {
-                let pending = PendingBackup {
-                    message_id: job.message_id.get(),
-                    channel_id: channel_id.get(),
-                    local_path: result.local_path.clone(),
-                    original_filename: result.filename.clone(),
-                    timestamp: job.timestamp,
-                    retry_count: 0,
-                    status: BackupStatus::Pending,
-                };
-                if let Err(e) = queue.add(pending) {
-                    error!(
-                        "Failed to add backup to queue for {}: {e:?}",
-                        result.local_path.display()
-                    );
-                    // Don't delete the message if we can't track it
-                    continue;
-                }
+                let _ = tokio::fs::remove_file(&result.local_path).await;
             }
+            continue;
+        }
+
+        if all_queued {
+            backed_up += results.len();
+        }
+
+        if uploads_enabled {
+            info!(
+                "Queued {} files from message {}, it will be deleted once they're uploaded",
+                results.len(),
+                job.message_id
+            );
+            continue;
+        }
+
+        // Don't delete the message if we can't track it
+        if !all_queued {
+            continue;
         }
 
         // NOW it's safe to delete Discord message
@@ -352,5 +893,131 @@ async fn process_backup_jobs(
         sleep(SINGLE_DELETE_DELAY).await;
     }
 
-    Ok(())
+    Ok(backed_up)
+}
+
+/// A pending backup for each of a backup job's downloaded files.
+fn pending_backups(
+    channel_id: ChannelId,
+    job: &BackupJob,
+    results: &[DownloadResult],
+) -> Vec<PendingBackup> {
+    results
+        .iter()
+        .map(|result| PendingBackup {
+            message_id: job.message_id.get(),
+            channel_id: channel_id.get(),
+            local_path: result.local_path.clone(),
+            original_filename: result.filename.clone(),
+            timestamp: job.timestamp,
+            retry_count: 0,
+            status: BackupStatus::Pending,
+            next_retry_at: None,
+            account: None,
+            upload_session: None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serenity::all::MessageId;
+
+    use super::*;
+    use crate::media::MediaAttachment;
+
+    /// Milliseconds from the Unix epoch to Discord's.
+    const DISCORD_EPOCH_MS: i64 = 1_420_070_400_000;
+
+    fn cutoff() -> Timestamp {
+        Timestamp::from_unix_timestamp(1_700_000_000).unwrap()
+    }
+
+    /// A job for a message created `offset_secs` after the cutoff.
+    fn job(offset_secs: i64) -> DeleteJob {
+        let created_ms = (cutoff().unix_timestamp() + offset_secs) * 1000;
+        DeleteJob {
+            message_id: MessageId::new(((created_ms - DISCORD_EPOCH_MS) as u64) << 22),
+        }
+    }
+
+    fn ids(jobs: &[&DeleteJob]) -> Vec<MessageId> {
+        jobs.iter().map(|j| j.message_id).collect()
+    }
+
+    #[test]
+    fn recent_messages_are_bulk_deleted() {
+        let jobs = [job(60), job(120), job(180)];
+
+        let (bulk, individual) = partition_deletes(&jobs, cutoff());
+
+        assert_eq!(
+            ids(&bulk),
+            jobs.iter().map(|j| j.message_id).collect::<Vec<_>>()
+        );
+        assert!(individual.is_empty());
+    }
+
+    #[test]
+    fn messages_past_the_bulk_delete_age_are_deleted_individually() {
+        let jobs = [job(-60), job(60), job(120), job(-120)];
+
+        let (bulk, individual) = partition_deletes(&jobs, cutoff());
+
+        assert_eq!(ids(&bulk), [jobs[1].message_id, jobs[2].message_id]);
+        assert_eq!(ids(&individual), [jobs[0].message_id, jobs[3].message_id]);
+    }
+
+    #[test]
+    fn a_single_recent_message_is_deleted_individually() {
+        let jobs = [job(-60), job(60)];
+
+        let (bulk, individual) = partition_deletes(&jobs, cutoff());
+
+        assert!(bulk.is_empty());
+        assert_eq!(ids(&individual), [jobs[0].message_id, jobs[1].message_id]);
+    }
+
+    #[test]
+    fn nothing_to_delete() {
+        let (bulk, individual) = partition_deletes(&[], cutoff());
+
+        assert!(bulk.is_empty());
+        assert!(individual.is_empty());
+    }
+
+    #[test]
+    fn each_attachment_of_a_job_is_queued() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut queue = BackupQueue::load_from(dir.path().join("pending_backups.toml"), None);
+        let attachment = |filename: &str| MediaAttachment {
+            url: format!("https://cdn.example/{filename}"),
+            filename: filename.to_string(),
+            size: 100,
+        };
+        let job = BackupJob {
+            message_id: MessageId::new(1),
+            attachments: vec![attachment("a.jpg"), attachment("b.png")],
+            timestamp: *cutoff(),
+        };
+        let results: Vec<_> = job
+            .attachments
+            .iter()
+            .map(|a| DownloadResult {
+                local_path: dir.path().join(format!("1_{}", a.filename)),
+                filename: a.filename.clone(),
+            })
+            .collect();
+
+        let pending = pending_backups(ChannelId::new(2), &job, &results);
+        queue.add_message(pending).unwrap();
+
+        for result in &results {
+            let backup = queue.get(&result.local_path).unwrap();
+            assert_eq!(backup.message_id, 1);
+            assert_eq!(backup.channel_id, 2);
+            assert_eq!(backup.original_filename, result.filename);
+        }
+        assert_eq!(queue.get_pending().len(), 2);
+    }
 }
@@ -1,24 +1,38 @@
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+use metrics_client::MetricsClient;
 use serenity::all::Http;
-use tokio::time::{MissedTickBehavior, interval};
+use tokio::time::{Instant, MissedTickBehavior, interval, interval_at, sleep};
 use tracing::{debug, info};
 
 use crate::backup::BackupQueue;
 use crate::cancellation::CancellationRegistry;
+use crate::cleanup::member_cache::MemberCache;
 use crate::cleanup::task::cleanup_channel;
 use crate::config::ConfigStore;
+use crate::metrics::Event;
 
-/// Spawn the cleanup scheduler task.
+/// Spawn the cleanup scheduler task. `uploads_enabled` is whether a backup worker is running to
+/// upload queued media.
 pub fn spawn_worker(
     http: Arc<Http>,
     config: ConfigStore,
     backup_queue: Arc<Mutex<BackupQueue>>,
     cancellation: Arc<Mutex<CancellationRegistry>>,
+    metrics: Option<MetricsClient<Event>>,
+    uploads_enabled: bool,
 ) -> tokio::task::JoinHandle<()> {
     tokio::spawn(async move {
-        run_worker(http, config, backup_queue, cancellation).await;
+        run_worker(
+            http,
+            config,
+            backup_queue,
+            cancellation,
+            metrics,
+            uploads_enabled,
+        )
+        .await;
     })
 }
 
@@ -27,10 +41,14 @@ async fn run_worker(
     config: ConfigStore,
     backup_queue: Arc<Mutex<BackupQueue>>,
     cancellation: Arc<Mutex<CancellationRegistry>>,
+    metrics: Option<MetricsClient<Event>>,
+    uploads_enabled: bool,
 ) {
-    let scheduler_interval = Duration::from_secs(config.schedule_interval_seconds().get() as u64);
+    let mut scheduler_interval =
+        Duration::from_secs(config.schedule_interval_seconds().get() as u64);
     let mut interval = interval(scheduler_interval);
     interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let member_cache = Arc::new(Mutex::new(MemberCache::new()));
 
     info!(
         "Cleanup scheduler started (interval: {:?})",
@@ -40,6 +58,16 @@ async fn run_worker(
     loop {
         interval.tick().await;
 
+        // The interval can change when the config is reloaded
+        let configured_interval =
+            Duration::from_secs(config.schedule_interval_seconds().get() as u64);
+        if configured_interval != scheduler_interval {
+            info!("Cleanup scheduler interval changed to {configured_interval:?}");
+            scheduler_interval = configured_interval;
+            interval = interval_at(Instant::now() + scheduler_interval, scheduler_interval);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        }
+
         // Get enabled channels snapshot
         let channels = config.enabled_channels();
 
@@ -53,12 +81,16 @@ async fn run_worker(
             channels.len()
         );
 
-        // Spawn independent cleanup tasks for each channel
-        for (channel_id, retention_days) in channels {
+        // Spawn independent cleanup tasks for each channel, starting them a stagger apart (plus up
+        // to half a stagger of jitter) so they don't all hit the API at once
+        let stagger = config.stagger();
+        for (index, (channel_id, retention_days)) in channels.into_iter().enumerate() {
             let http = Arc::clone(&http);
             let config = config.clone();
             let backup_queue = Arc::clone(&backup_queue);
+            let member_cache = Arc::clone(&member_cache);
             let cancellation_registry = Arc::clone(&cancellation);
+            let metrics = metrics.clone();
 
             // Check and register atomically to prevent race condition
             let cancel_token = {
@@ -73,19 +105,26 @@ async fn run_worker(
                 registry.register(channel_id)
             };
 
+            let jitter = stagger.mul_f64(fastrand::f64() / 2.0);
+            let delay = stagger * index as u32 + jitter;
+
             debug!(
-                "Spawning cleanup task for channel {} (retention: {} days)",
-                channel_id, retention_days
+                "Spawning cleanup task for channel {} in {:?} (retention: {} days)",
+                channel_id, delay, retention_days
             );
 
             tokio::spawn(async move {
+                sleep(delay).await;
                 cleanup_channel(
                     http,
                     config,
                     backup_queue,
+                    member_cache,
                     cancellation_registry,
+                    metrics,
                     channel_id,
                     retention_days,
+                    uploads_enabled,
                     cancel_token,
                 )
                 .await;
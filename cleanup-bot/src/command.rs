@@ -1,21 +1,40 @@
+use std::collections::HashMap;
 use std::num::NonZeroU32;
 use std::sync::{Arc, Mutex};
 
 use anyhow::{Error, Result};
 use indoc::formatdoc;
-use serenity::all::Mentionable;
+use poise::CreateReply;
+use serenity::all::{ChannelId, Mentionable};
 
+use crate::backup::{BackupQueue, BackupStatus};
 use crate::cancellation::CancellationRegistry;
 use crate::config::{ChannelConfig, ConfigStore};
 
 pub struct CommandData {
     pub config: ConfigStore,
     pub cancellation: Arc<Mutex<CancellationRegistry>>,
+    pub backup_queue: Arc<Mutex<BackupQueue>>,
 }
 
 type Context<'a> = poise::Context<'a, CommandData, Error>;
 
-#[poise::command(slash_command, subcommands("enable", "disable"))]
+/// How many channels `/cleanup list` shows per message.
+const CHANNELS_PER_MESSAGE: usize = 25;
+
+/// Discord's limit on a message's length.
+const MESSAGE_LENGTH_LIMIT: usize = 2000;
+
+/// Room kept at the end of each page of `/cleanup backups` for its page number.
+const PAGE_FOOTER_LEN: usize = 20;
+
+/// How many characters of each backup's error `/cleanup backups` shows.
+const BACKUP_ERROR_MAX_CHARS: usize = 150;
+
+#[poise::command(
+    slash_command,
+    subcommands("enable", "disable", "status", "list", "backups", "reset_cursor")
+)]
 pub async fn cleanup(_ctx: Context<'_>) -> Result<()> {
     Ok(())
 }
@@ -29,8 +48,15 @@ pub async fn enable(
 ) -> Result<()> {
     let channel_config = ChannelConfig {
         name: ctx.channel_id().name(&ctx.http()).await?,
+        guild_id: ctx.guild_id(),
         policy_days,
+        min_age_days: None,
         pagination_cursor: None,
+        preserve_user_ids: Vec::new(),
+        preserve_role_ids: Vec::new(),
+        include_threads: true,
+        include_archived_threads: false,
+        thread_cursors: HashMap::new(),
     };
 
     let policy_days = ctx
@@ -43,7 +69,7 @@ pub async fn enable(
         Retention policy: **{policy_days} {day_suffix}**
         ",
         channel = ctx.channel_id().mention(),
-        day_suffix = if policy_days.get() == 1 {"day"}  else {"days"}
+        day_suffix = day_suffix(policy_days),
     })
     .await?;
     Ok(())
@@ -73,3 +99,253 @@ pub async fn disable(ctx: Context<'_>) -> Result<()> {
     ctx.say(message).await?;
     Ok(())
 }
+
+#[poise::command(slash_command)]
+pub async fn status(ctx: Context<'_>) -> Result<()> {
+    let channel_id = ctx.channel_id();
+    let config = &ctx.data().config;
+
+    let Some(policy_days) = config.channel_policy_days(channel_id) else {
+        ctx.send(
+            CreateReply::default()
+                .content(format!(
+                    "Cleanup is not enabled for {channel}",
+                    channel = channel_id.mention()
+                ))
+                .ephemeral(true),
+        )
+        .await?;
+        return Ok(());
+    };
+
+    let running = ctx
+        .data()
+        .cancellation
+        .lock()
+        .unwrap()
+        .is_running(channel_id);
+
+    let cursor = match config.get_pagination_cursor(channel_id) {
+        Some(cursor) => format!("before message `{cursor}`"),
+        None => "newest messages".to_string(),
+    };
+
+    ctx.send(
+        CreateReply::default()
+            .content(formatdoc! {"
+                Cleanup is enabled for {channel}
+                Retention policy: **{policy_days} {day_suffix}**
+                Cleanup task: **{task}**
+                Next run starts from: {cursor}
+                ",
+                channel = channel_id.mention(),
+                day_suffix = day_suffix(policy_days),
+                task = if running {"running"} else {"idle"},
+            })
+            .ephemeral(true),
+    )
+    .await?;
+    Ok(())
+}
+
+#[poise::command(slash_command)]
+pub async fn list(ctx: Context<'_>) -> Result<()> {
+    let channels = ctx.data().config.enabled_channels_by_name();
+
+    if channels.is_empty() {
+        ctx.send(
+            CreateReply::default()
+                .content("Cleanup is not enabled for any channels")
+                .ephemeral(true),
+        )
+        .await?;
+        return Ok(());
+    }
+
+    // Split long lists across messages to stay under Discord's message length limit
+    let pages = channels.chunks(CHANNELS_PER_MESSAGE).count();
+    for (page, chunk) in channels.chunks(CHANNELS_PER_MESSAGE).enumerate() {
+        let mut message = if page == 0 {
+            format!("Cleanup is enabled for {} channels:\n", channels.len())
+        } else {
+            String::new()
+        };
+
+        for (channel_id, _, policy_days) in chunk {
+            message.push_str(&format!(
+                "- {channel}: **{policy_days} {day_suffix}**\n",
+                channel = channel_id.mention(),
+                day_suffix = day_suffix(*policy_days),
+            ));
+        }
+
+        if pages > 1 {
+            message.push_str(&format!("_Page {}/{pages}_", page + 1));
+        }
+
+        ctx.send(CreateReply::default().content(message).ephemeral(true))
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Starts the channel's next cleanup run from its newest messages again.
+#[poise::command(slash_command, rename = "reset-cursor")]
+pub async fn reset_cursor(ctx: Context<'_>) -> Result<()> {
+    let channel_id = ctx.channel_id();
+    let config = &ctx.data().config;
+
+    if config.channel_policy_days(channel_id).is_none() {
+        ctx.send(
+            CreateReply::default()
+                .content(format!(
+                    "Cleanup is not enabled for {channel}",
+                    channel = channel_id.mention()
+                ))
+                .ephemeral(true),
+        )
+        .await?;
+        return Ok(());
+    }
+
+    config.set_pagination_cursor(channel_id, None)?;
+    config.retain_thread_cursors(channel_id, &[])?;
+
+    ctx.say(format!(
+        "Reset the cleanup cursor for {channel}, the next run will start from the newest messages",
+        channel = channel_id.mention()
+    ))
+    .await?;
+    Ok(())
+}
+
+/// Lists backups that ran out of retries, and so will never be uploaded.
+#[poise::command(slash_command)]
+pub async fn backups(ctx: Context<'_>) -> Result<()> {
+    let mut dead_lettered: Vec<_> = ctx
+        .data()
+        .backup_queue
+        .lock()
+        .unwrap()
+        .get_dead_lettered()
+        .into_iter()
+        .cloned()
+        .collect();
+
+    if dead_lettered.is_empty() {
+        ctx.send(
+            CreateReply::default()
+                .content("No backups have run out of retries")
+                .ephemeral(true),
+        )
+        .await?;
+        return Ok(());
+    }
+
+    dead_lettered.sort_by_key(|b| b.timestamp);
+
+    let lines: Vec<_> = dead_lettered
+        .iter()
+        .filter_map(|backup| {
+            let BackupStatus::DeadLettered { error } = &backup.status else {
+                return None;
+            };
+            Some(format!(
+                "- `{filename}` from {channel} after {attempts} attempts: {error}\n",
+                filename = backup.original_filename,
+                channel = ChannelId::new(backup.channel_id).mention(),
+                attempts = backup.retry_count,
+                error = truncate(error, BACKUP_ERROR_MAX_CHARS),
+            ))
+        })
+        .collect();
+
+    let header = format!("{} backups ran out of retries:\n", dead_lettered.len());
+    let pages = paginate(header, &lines);
+    let page_count = pages.len();
+    for (page, mut message) in pages.into_iter().enumerate() {
+        if page_count > 1 {
+            message.push_str(&format!("_Page {}/{page_count}_", page + 1));
+        }
+
+        ctx.send(CreateReply::default().content(message).ephemeral(true))
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Split `lines` across messages, after `header`, leaving each one room for a page number
+/// within Discord's message length limit. Lengths are counted in bytes, which never undercounts
+/// the characters Discord limits.
+fn paginate(header: String, lines: &[String]) -> Vec<String> {
+    let mut pages = vec![header];
+    for line in lines {
+        let page = pages.last_mut().expect("there is always a page");
+        if !page.is_empty() && page.len() + line.len() + PAGE_FOOTER_LEN > MESSAGE_LENGTH_LIMIT {
+            pages.push(String::new());
+        }
+        pages
+            .last_mut()
+            .expect("there is always a page")
+            .push_str(line);
+    }
+    pages
+}
+
+/// Cut `text` down to `max_chars` characters, marking where it was cut.
+fn truncate(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+fn day_suffix(days: NonZeroU32) -> &'static str {
+    if days.get() == 1 { "day" } else { "days" }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncate_keeps_short_text() {
+        assert_eq!(truncate("short", 5), "short");
+    }
+
+    #[test]
+    fn truncate_cuts_long_text_on_a_char_boundary() {
+        assert_eq!(truncate("héllo wörld", 7), "héllo w…");
+    }
+
+    #[test]
+    fn pages_stay_under_the_message_limit() {
+        let lines: Vec<_> = (0..100)
+            .map(|i| format!("- line {i}: {}\n", "x".repeat(BACKUP_ERROR_MAX_CHARS)))
+            .collect();
+
+        let pages = paginate("Header\n".to_string(), &lines);
+
+        assert!(pages.len() > 1);
+        assert!(pages[0].starts_with("Header\n"));
+        for page in &pages {
+            assert!(
+                page.len() + PAGE_FOOTER_LEN <= MESSAGE_LENGTH_LIMIT,
+                "{}",
+                page.len()
+            );
+        }
+        assert_eq!(pages.concat(), format!("Header\n{}", lines.concat()));
+    }
+
+    #[test]
+    fn few_lines_fit_on_one_page() {
+        let lines = vec!["- one\n".to_string(), "- two\n".to_string()];
+        assert_eq!(
+            paginate("Header\n".to_string(), &lines),
+            ["Header\n- one\n- two\n"]
+        );
+    }
+}
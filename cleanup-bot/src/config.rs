@@ -1,42 +1,143 @@
 use std::{
     collections::HashMap,
-    fs,
+    env, fs,
     num::NonZeroU32,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
 };
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, anyhow};
 use serde::{Deserialize, Serialize};
-use serenity::all::ChannelId;
+use serenity::all::{ChannelId, GuildId, RoleId, UserId};
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+use crate::media::MediaAttachment;
 
 const CONFIG_PATH: &str = "./config.toml";
 const CONFIG_TEMP_PATH: &str = "./config.toml.tmp";
+/// How often the config file is checked for changes.
+const CONFIG_RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+const DRY_RUN_FLAG: &str = "--dry-run";
+const DRY_RUN_ENV: &str = "DRY_RUN";
+/// Service identifier reported with metrics when `BOT_NAME` is unset.
+const DEFAULT_METRICS_SOURCE: &str = "cleanup-bot";
+/// Seconds between metrics heartbeats when `METRICS_HEARTBEAT_INTERVAL` is unset.
+const DEFAULT_HEARTBEAT_INTERVAL_SECS: u64 = 30;
 
 fn default_upload_folder() -> String {
     "/discord-backups".to_string()
 }
 
+fn default_account_name() -> String {
+    "default".to_string()
+}
+
+fn default_tokens_path() -> PathBuf {
+    PathBuf::from("./onedrive_tokens.toml")
+}
+
+/// What OneDrive does when an upload's path already exists.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictBehavior {
+    /// Keep both, with OneDrive adding a number to the new file's name
+    Rename,
+    /// Overwrite the existing file
+    #[default]
+    Replace,
+    /// Fail the upload, leaving it to be retried
+    Fail,
+}
+
+impl ConflictBehavior {
+    /// The value of Graph's `@microsoft.graph.conflictBehavior`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ConflictBehavior::Rename => "rename",
+            ConflictBehavior::Replace => "replace",
+            ConflictBehavior::Fail => "fail",
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct OneDriveConfig {
+    /// Identifies the account when there are several
+    #[serde(default = "default_account_name")]
+    pub name: String,
     pub client_id: String,
     #[serde(default = "default_upload_folder")]
     pub upload_folder: String,
+    /// Where the OneDrive access and refresh tokens are stored
+    #[serde(default = "default_tokens_path")]
+    pub tokens_path: PathBuf,
+    #[serde(default)]
+    pub conflict_behavior: ConflictBehavior,
+}
+
+/// One OneDrive account as an `[onedrive]` table, or several as `[[onedrive]]` tables that
+/// backups are spread across.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum OneDriveAccountsConfig {
+    One(OneDriveConfig),
+    Many(Vec<OneDriveConfig>),
+}
+
+impl OneDriveAccountsConfig {
+    pub fn accounts(&self) -> &[OneDriveConfig] {
+        match self {
+            OneDriveAccountsConfig::One(account) => std::slice::from_ref(account),
+            OneDriveAccountsConfig::Many(accounts) => accounts,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ChannelConfig {
     pub name: String,
+    /// Guild the channel belongs to, for the guild's retention default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guild_id: Option<GuildId>,
     /// Override for the global retention policy
     pub policy_days: Option<NonZeroU32>,
+    /// Messages younger than this are kept, even if the retention policy is shorter
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_age_days: Option<NonZeroU32>,
     /// Pagination cursor: oldest message ID seen, next run fetches BEFORE this
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub pagination_cursor: Option<u64>,
+    /// Users whose messages are never cleaned up
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub preserve_user_ids: Vec<UserId>,
+    /// Roles whose members' messages are never cleaned up
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub preserve_role_ids: Vec<RoleId>,
+    /// Also clean up the channel's active threads and forum posts
+    #[serde(default = "default_include_threads")]
+    pub include_threads: bool,
+    /// Also clean up recently archived public threads
+    #[serde(default)]
+    pub include_archived_threads: bool,
+    /// Each thread's pagination cursor, kept apart from the channel's own
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub thread_cursors: HashMap<ChannelId, u64>,
+}
+
+fn default_include_threads() -> bool {
+    true
 }
 
 impl ChannelConfig {
     pub fn resolve_policy_days(&self, config: &Config) -> NonZeroU32 {
         self.policy_days
+            .or_else(|| {
+                self.guild_id
+                    .and_then(|guild_id| config.retention.guild_policy_days.get(&guild_id))
+                    .copied()
+            })
             .unwrap_or(config.retention.default_policy_days)
     }
 }
@@ -44,6 +145,9 @@ impl ChannelConfig {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RetentionConfig {
     pub default_policy_days: NonZeroU32,
+    /// Per-guild overrides of the default, for channels without their own policy
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub guild_policy_days: HashMap<GuildId, NonZeroU32>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -52,6 +156,23 @@ pub struct BackupWorkerConfig {
     pub check_interval_seconds: u64,
     #[serde(default = "default_max_retries")]
     pub max_retries: u32,
+    /// Wait before the first retry of a failed upload, doubling with each further failure
+    #[serde(default = "default_retry_base_seconds")]
+    pub retry_base_seconds: u64,
+    /// Longest wait between retries
+    #[serde(default = "default_retry_max_seconds")]
+    pub retry_max_seconds: u64,
+    /// How many files to upload at once
+    #[serde(default = "default_max_concurrent_uploads")]
+    pub max_concurrent_uploads: usize,
+}
+
+fn default_shutdown_grace_seconds() -> u64 {
+    30
+}
+
+fn default_stagger_ms() -> u64 {
+    500
 }
 
 fn default_check_interval() -> u64 {
@@ -62,20 +183,102 @@ fn default_max_retries() -> u32 {
     5
 }
 
+fn default_retry_base_seconds() -> u64 {
+    60
+}
+
+fn default_retry_max_seconds() -> u64 {
+    3600
+}
+
+fn default_max_concurrent_uploads() -> usize {
+    2
+}
+
 impl Default for BackupWorkerConfig {
     fn default() -> Self {
         Self {
             check_interval_seconds: default_check_interval(),
             max_retries: default_max_retries(),
+            retry_base_seconds: default_retry_base_seconds(),
+            retry_max_seconds: default_retry_max_seconds(),
+            max_concurrent_uploads: default_max_concurrent_uploads(),
         }
     }
 }
 
+/// Where downloaded media is backed up to.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BackupTargetConfig {
+    /// Upload to OneDrive, using the `[onedrive]` section. Without one, media is only kept locally.
+    #[default]
+    OneDrive,
+    /// Copy into another directory, e.g. a mounted network share.
+    LocalCopy { directory: PathBuf },
+}
+
+/// What the backup queue does with a new backup when it's full.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum QueueOverflowPolicy {
+    /// Turn the backup away, leaving its message in Discord for a later run
+    #[default]
+    Reject,
+    /// Drop the oldest message whose backups haven't been attempted yet. Its message stays in
+    /// Discord, so a later run backs it up again
+    EvictOldest,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct QueueLimit {
+    pub max_entries: usize,
+    #[serde(default)]
+    pub overflow: QueueOverflowPolicy,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MediaBackupConfig {
     pub download_dir: PathBuf,
     #[serde(default)]
     pub worker: BackupWorkerConfig,
+    #[serde(default)]
+    pub target: BackupTargetConfig,
+    /// Downloads that would push `download_dir` past this many bytes are deferred
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_download_dir_bytes: Option<u64>,
+    /// Upload each day's pending media as one zip rather than file by file
+    #[serde(default)]
+    pub archive_daily: bool,
+    /// Caps how many files can be waiting in the backup queue
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queue_limit: Option<QueueLimit>,
+    /// Only back up media with these file extensions (all media if empty). Anything else is
+    /// deleted with its message
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extensions: Vec<String>,
+    /// Media larger than this is deleted with its message rather than backed up
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_attachment_bytes: Option<u64>,
+}
+
+impl MediaBackupConfig {
+    /// Whether a media attachment passes the extension allowlist and size cap.
+    pub fn should_back_up(&self, attachment: &MediaAttachment) -> bool {
+        let extension_allowed = self.extensions.is_empty()
+            || Path::new(&attachment.filename)
+                .extension()
+                .is_some_and(|extension| {
+                    self.extensions.iter().any(|allowed| {
+                        extension.eq_ignore_ascii_case(allowed.trim_start_matches('.'))
+                    })
+                });
+        let size_allowed = self
+            .max_attachment_bytes
+            .is_none_or(|max_bytes| attachment.size <= max_bytes);
+
+        extension_allowed && size_allowed
+    }
 }
 
 impl Default for MediaBackupConfig {
@@ -83,6 +286,12 @@ impl Default for MediaBackupConfig {
         Self {
             download_dir: PathBuf::from("./media_backups"),
             worker: BackupWorkerConfig::default(),
+            target: BackupTargetConfig::default(),
+            max_download_dir_bytes: None,
+            archive_daily: false,
+            queue_limit: None,
+            extensions: Vec::new(),
+            max_attachment_bytes: None,
         }
     }
 }
@@ -90,21 +299,64 @@ impl Default for MediaBackupConfig {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
     pub schedule_interval_seconds: NonZeroU32,
+    /// Delay between starting each channel's cleanup on a tick, to spread out API calls
+    #[serde(default = "default_stagger_ms")]
+    pub stagger_ms: u64,
     pub retention: RetentionConfig,
     pub media_backup: MediaBackupConfig,
     #[serde(default)]
-    pub onedrive: Option<OneDriveConfig>,
+    pub onedrive: Option<OneDriveAccountsConfig>,
+    /// Log what cleanup would delete and back up, without touching any messages
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Channel to post a report to after each channel's cleanup
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audit_channel_id: Option<ChannelId>,
+    /// Remove channels that no longer exist in Discord from the config at startup
+    #[serde(default)]
+    pub prune_missing_channels: bool,
+    /// How long shutdown waits for running cleanups and uploads to finish
+    #[serde(default = "default_shutdown_grace_seconds")]
+    pub shutdown_grace_seconds: u64,
     #[serde(default)]
     channels: HashMap<ChannelId, ChannelConfig>,
 }
 
 impl Config {
     pub fn load() -> Result<Self> {
-        let bytes = fs::read(CONFIG_PATH).context(format!("Error reading {CONFIG_PATH}"))?;
-        let config = toml::from_slice(bytes.as_slice())?;
+        let content =
+            fs::read_to_string(CONFIG_PATH).context(format!("Error reading {CONFIG_PATH}"))?;
+        Self::parse(&content)
+    }
+
+    /// Parse the contents of the config file.
+    pub fn parse(content: &str) -> Result<Self> {
+        // The TOML error says which line and column are at fault
+        let config = toml::from_str(content).context(format!("Error parsing {CONFIG_PATH}"))?;
         Ok(config)
     }
 
+    /// Carry over pagination cursors from `current` (the in-memory config) wherever the reloaded
+    /// file left them as they were in `previous` (the file as last read), so a reload doesn't
+    /// rewind cleanup to whatever cursor was last saved.
+    fn merge_cursors(&mut self, previous: &Config, current: &Config) {
+        for (channel_id, channel) in &mut self.channels {
+            let (Some(previous), Some(current)) = (
+                previous.channels.get(channel_id),
+                current.channels.get(channel_id),
+            ) else {
+                continue;
+            };
+
+            if channel.pagination_cursor == previous.pagination_cursor {
+                channel.pagination_cursor = current.pagination_cursor;
+            }
+            if channel.thread_cursors == previous.thread_cursors {
+                channel.thread_cursors = current.thread_cursors.clone();
+            }
+        }
+    }
+
     pub fn save(&self) -> Result<()> {
         let content = toml::to_string_pretty(&self)?;
         fs::write(CONFIG_TEMP_PATH, &content).context("saving temp config file")?;
@@ -115,19 +367,27 @@ impl Config {
     pub fn add_channel_config(
         &mut self,
         channel_id: ChannelId,
-        config: ChannelConfig,
+        mut config: ChannelConfig,
     ) -> Result<NonZeroU32> {
-        let new_days = config
-            .policy_days
-            .unwrap_or(self.retention.default_policy_days);
+        let new_days = config.resolve_policy_days(self);
 
-        // Check if policy is becoming stricter (fewer days) - if so, clear pagination cursor
         if let Some(existing) = self.channels.get(&channel_id) {
+            // Preserve lists are only set in the config file, so keep them when re-enabling
+            if config.preserve_user_ids.is_empty() && config.preserve_role_ids.is_empty() {
+                config.preserve_user_ids = existing.preserve_user_ids.clone();
+                config.preserve_role_ids = existing.preserve_role_ids.clone();
+            }
+            // As are the age floor and thread settings
+            config.min_age_days = existing.min_age_days;
+            config.include_threads = existing.include_threads;
+            config.include_archived_threads = existing.include_archived_threads;
+
+            // Check if policy is becoming stricter (fewer days) - if so, clear pagination cursor
             let old_days = existing.resolve_policy_days(self);
             if new_days < old_days {
                 // Policy is stricter, start fresh from newest messages
-                let mut config = config;
                 config.pagination_cursor = None;
+                config.thread_cursors.clear();
                 self.channels.insert(channel_id, config);
                 self.save()?;
                 return Ok(new_days);
@@ -157,11 +417,103 @@ impl Config {
         Ok(())
     }
 
+    pub fn get_thread_cursor(&self, channel_id: ChannelId, thread_id: ChannelId) -> Option<u64> {
+        self.channels
+            .get(&channel_id)
+            .and_then(|c| c.thread_cursors.get(&thread_id).copied())
+    }
+
+    pub fn set_thread_cursor(
+        &mut self,
+        channel_id: ChannelId,
+        thread_id: ChannelId,
+        cursor: Option<u64>,
+    ) -> Result<()> {
+        if let Some(config) = self.channels.get_mut(&channel_id) {
+            match cursor {
+                Some(cursor) => config.thread_cursors.insert(thread_id, cursor),
+                None => config.thread_cursors.remove(&thread_id),
+            };
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Drops the cursors of threads that are no longer cleaned up, e.g. deleted ones.
+    pub fn retain_thread_cursors(
+        &mut self,
+        channel_id: ChannelId,
+        thread_ids: &[ChannelId],
+    ) -> Result<()> {
+        if let Some(config) = self.channels.get_mut(&channel_id) {
+            let before = config.thread_cursors.len();
+            config
+                .thread_cursors
+                .retain(|thread_id, _| thread_ids.contains(thread_id));
+            if config.thread_cursors.len() != before {
+                self.save()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn min_age_days(&self, channel_id: ChannelId) -> Option<NonZeroU32> {
+        self.channels.get(&channel_id).and_then(|c| c.min_age_days)
+    }
+
+    /// Returns whether a channel's threads are cleaned up, and whether that includes archived
+    /// ones.
+    pub fn thread_options(&self, channel_id: ChannelId) -> (bool, bool) {
+        self.channels
+            .get(&channel_id)
+            .map(|c| (c.include_threads, c.include_archived_threads))
+            .unwrap_or_default()
+    }
+
+    /// Returns all enabled channels with their names and resolved retention policies, sorted by
+    /// name.
+    pub fn enabled_channels_by_name(&self) -> Vec<(ChannelId, String, NonZeroU32)> {
+        let mut channels: Vec<_> = self
+            .channels
+            .iter()
+            .map(|(id, config)| (*id, config.name.clone(), config.resolve_policy_days(self)))
+            .collect();
+        channels.sort_by(|a, b| a.1.cmp(&b.1));
+        channels
+    }
+
+    /// Returns the resolved retention policy for a channel, or `None` if cleanup isn't enabled.
+    pub fn channel_policy_days(&self, channel_id: ChannelId) -> Option<NonZeroU32> {
+        self.channels
+            .get(&channel_id)
+            .map(|c| c.resolve_policy_days(self))
+    }
+
+    /// Returns the users and roles whose messages are never cleaned up in a channel.
+    pub fn preserved(&self, channel_id: ChannelId) -> (Vec<UserId>, Vec<RoleId>) {
+        self.channels
+            .get(&channel_id)
+            .map(|c| (c.preserve_user_ids.clone(), c.preserve_role_ids.clone()))
+            .unwrap_or_default()
+    }
+
     pub fn remove_channel(&mut self, channel_id: ChannelId) -> Result<()> {
         self.channels.remove(&channel_id);
         self.save()
     }
 
+    /// Records the guild of a channel enabled before guilds were tracked, so the guild's
+    /// retention default applies to it. Leaves a channel that already has one alone.
+    pub fn backfill_guild_id(&mut self, channel_id: ChannelId, guild_id: GuildId) -> Result<()> {
+        if let Some(config) = self.channels.get_mut(&channel_id)
+            && config.guild_id.is_none()
+        {
+            config.guild_id = Some(guild_id);
+            self.save()?;
+        }
+        Ok(())
+    }
+
     /// Returns a list of all enabled channels with their resolved retention policies.
     pub fn enabled_channels(&self) -> Vec<(ChannelId, NonZeroU32)> {
         self.channels
@@ -171,29 +523,213 @@ impl Config {
     }
 }
 
+/// Config for reporting metrics to a service-panel instance, read from the environment like the
+/// Discord token.
+pub struct MetricsConfig {
+    /// Service identifier reported with every metric and heartbeat. Read from `BOT_NAME`.
+    pub source: String,
+    pub ingest_endpoint: String,
+    pub heartbeat_endpoint: String,
+    pub heartbeat_interval: Duration,
+}
+
+impl MetricsConfig {
+    /// Reads the optional metrics config.
+    ///
+    /// Metrics are enabled only when both `METRICS_INGEST_ENDPOINT` and
+    /// `METRICS_HEARTBEAT_ENDPOINT` are set. Setting only one is treated as a misconfiguration so
+    /// a typo doesn't silently disable reporting.
+    pub fn from_env() -> Result<Option<Self>> {
+        // Treat a blank value the same as unset.
+        let read = |key| env::var(key).ok().filter(|value| !value.is_empty());
+
+        match (
+            read("METRICS_INGEST_ENDPOINT"),
+            read("METRICS_HEARTBEAT_ENDPOINT"),
+        ) {
+            (None, None) => Ok(None),
+            (Some(ingest_endpoint), Some(heartbeat_endpoint)) => {
+                let heartbeat_interval = match read("METRICS_HEARTBEAT_INTERVAL") {
+                    Some(secs) => {
+                        let secs: u64 = secs
+                            .parse()
+                            .context("METRICS_HEARTBEAT_INTERVAL must be a number of seconds")?;
+                        // A zero interval would panic `tokio::time::interval`.
+                        if secs == 0 {
+                            return Err(anyhow!(
+                                "METRICS_HEARTBEAT_INTERVAL must be greater than zero"
+                            ));
+                        }
+                        Duration::from_secs(secs)
+                    }
+                    None => Duration::from_secs(DEFAULT_HEARTBEAT_INTERVAL_SECS),
+                };
+
+                Ok(Some(Self {
+                    source: read("BOT_NAME").unwrap_or_else(|| DEFAULT_METRICS_SOURCE.to_owned()),
+                    ingest_endpoint,
+                    heartbeat_endpoint,
+                    heartbeat_interval,
+                }))
+            }
+            _ => Err(anyhow!(
+                "METRICS_INGEST_ENDPOINT and METRICS_HEARTBEAT_ENDPOINT must both be set or both unset"
+            )),
+        }
+    }
+}
+
+/// When the config file was last modified, or `None` if that can't be read.
+fn config_modified() -> Option<SystemTime> {
+    fs::metadata(CONFIG_PATH).and_then(|m| m.modified()).ok()
+}
+
+/// Returns whether dry-run mode was requested with `--dry-run` or the `DRY_RUN` env var.
+fn dry_run_requested() -> bool {
+    env::args().any(|arg| arg == DRY_RUN_FLAG)
+        || env::var(DRY_RUN_ENV).is_ok_and(|value| value == "1" || value == "true")
+}
+
 /// Thread-safe wrapper around Config for clean state management.
 #[derive(Clone)]
 pub struct ConfigStore {
     inner: Arc<Mutex<Config>>,
+    /// Kept apart from `Config` so a command-line override isn't saved to the config file
+    dry_run: bool,
 }
 
 impl ConfigStore {
     pub fn new(config: Config) -> Self {
         Self {
+            dry_run: config.dry_run || dry_run_requested(),
             inner: Arc::new(Mutex::new(config)),
         }
     }
 
+    /// Watch the config file, swapping in its new contents whenever it changes so schedule,
+    /// retention and channel changes apply without a restart. A file that fails to parse is
+    /// ignored, keeping the current config. Dry run and the backup, OneDrive and metrics settings
+    /// are only read at startup.
+    pub fn spawn_reloader(&self) -> JoinHandle<()> {
+        let store = self.clone();
+        tokio::spawn(async move {
+            let mut last_modified = config_modified();
+            let mut last_contents = fs::read_to_string(CONFIG_PATH).unwrap_or_default();
+            let mut interval = tokio::time::interval(CONFIG_RELOAD_INTERVAL);
+
+            loop {
+                interval.tick().await;
+
+                let modified = config_modified();
+                if modified == last_modified {
+                    continue;
+                }
+                last_modified = modified;
+
+                let contents = match fs::read_to_string(CONFIG_PATH) {
+                    Ok(contents) => contents,
+                    Err(e) => {
+                        warn!("Error reading {CONFIG_PATH} to reload it: {e:?}");
+                        continue;
+                    }
+                };
+                if contents == last_contents {
+                    continue;
+                }
+
+                // Keep comparing against the last good file until a fixed one is saved
+                if store.reload(&last_contents, &contents) {
+                    last_contents = contents;
+                }
+            }
+        })
+    }
+
+    /// Swap in a changed config file. Returns whether it parsed.
+    fn reload(&self, previous_contents: &str, contents: &str) -> bool {
+        let mut config: Config = match toml::from_str(contents) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("Error parsing {CONFIG_PATH}, keeping the current config: {e}");
+                return false;
+            }
+        };
+
+        let mut current = self.inner.lock().unwrap();
+
+        // Most changes are the bot's own saves, e.g. of pagination cursors
+        if toml::to_string_pretty(&*current).is_ok_and(|saved| saved == contents) {
+            return true;
+        }
+
+        if let Ok(previous) = toml::from_str::<Config>(previous_contents) {
+            config.merge_cursors(&previous, &current);
+        }
+
+        // The backup worker is set up once at startup
+        let target = |config: &Config| {
+            serde_json::to_value((&config.media_backup.target, &config.onedrive)).ok()
+        };
+        if target(&config) != target(&current) {
+            warn!("The backup target in {CONFIG_PATH} changed, restart for it to take effect");
+        }
+        *current = config;
+
+        info!("Reloaded {CONFIG_PATH}");
+        true
+    }
+
+    /// Returns whether cleanup only logs what it would do.
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Returns the channel cleanup reports are posted to, if any.
+    pub fn audit_channel_id(&self) -> Option<ChannelId> {
+        self.inner.lock().unwrap().audit_channel_id
+    }
+
+    /// Returns how long shutdown waits for running work to finish.
+    pub fn shutdown_grace(&self) -> Duration {
+        Duration::from_secs(self.inner.lock().unwrap().shutdown_grace_seconds)
+    }
+
+    /// Returns whether channels missing from Discord are removed at startup.
+    pub fn prune_missing_channels(&self) -> bool {
+        self.inner.lock().unwrap().prune_missing_channels
+    }
+
     /// Returns the schedule interval in seconds.
     pub fn schedule_interval_seconds(&self) -> NonZeroU32 {
         self.inner.lock().unwrap().schedule_interval_seconds
     }
 
+    /// Returns the delay between starting each channel's cleanup.
+    pub fn stagger(&self) -> Duration {
+        Duration::from_millis(self.inner.lock().unwrap().stagger_ms)
+    }
+
     /// Returns a list of all enabled channels with their resolved retention policies.
     pub fn enabled_channels(&self) -> Vec<(ChannelId, NonZeroU32)> {
         self.inner.lock().unwrap().enabled_channels()
     }
 
+    /// Returns all enabled channels with their names and resolved retention policies, sorted by
+    /// name.
+    pub fn enabled_channels_by_name(&self) -> Vec<(ChannelId, String, NonZeroU32)> {
+        self.inner.lock().unwrap().enabled_channels_by_name()
+    }
+
+    /// Returns the resolved retention policy for a channel, or `None` if cleanup isn't enabled.
+    pub fn channel_policy_days(&self, channel_id: ChannelId) -> Option<NonZeroU32> {
+        self.inner.lock().unwrap().channel_policy_days(channel_id)
+    }
+
+    /// Returns the users and roles whose messages are never cleaned up in a channel.
+    pub fn preserved(&self, channel_id: ChannelId) -> (Vec<UserId>, Vec<RoleId>) {
+        self.inner.lock().unwrap().preserved(channel_id)
+    }
+
     /// Returns the media backup configuration.
     pub fn media_backup_config(&self) -> MediaBackupConfig {
         self.inner.lock().unwrap().media_backup.clone()
@@ -213,6 +749,14 @@ impl ConfigStore {
         self.inner.lock().unwrap().remove_channel(channel_id)
     }
 
+    /// Sets a channel's guild if it doesn't have one yet.
+    pub fn backfill_guild_id(&self, channel_id: ChannelId, guild_id: GuildId) -> Result<()> {
+        self.inner
+            .lock()
+            .unwrap()
+            .backfill_guild_id(channel_id, guild_id)
+    }
+
     /// Gets the pagination cursor for a channel.
     pub fn get_pagination_cursor(&self, channel_id: ChannelId) -> Option<u64> {
         self.inner.lock().unwrap().get_pagination_cursor(channel_id)
@@ -225,4 +769,181 @@ impl ConfigStore {
             .unwrap()
             .set_pagination_cursor(channel_id, cursor)
     }
+
+    /// Gets the pagination cursor for one of a channel's threads.
+    pub fn get_thread_cursor(&self, channel_id: ChannelId, thread_id: ChannelId) -> Option<u64> {
+        self.inner
+            .lock()
+            .unwrap()
+            .get_thread_cursor(channel_id, thread_id)
+    }
+
+    /// Sets the pagination cursor for one of a channel's threads.
+    pub fn set_thread_cursor(
+        &self,
+        channel_id: ChannelId,
+        thread_id: ChannelId,
+        cursor: Option<u64>,
+    ) -> Result<()> {
+        self.inner
+            .lock()
+            .unwrap()
+            .set_thread_cursor(channel_id, thread_id, cursor)
+    }
+
+    /// Drops the cursors of a channel's threads that aren't in `thread_ids`.
+    pub fn retain_thread_cursors(
+        &self,
+        channel_id: ChannelId,
+        thread_ids: &[ChannelId],
+    ) -> Result<()> {
+        self.inner
+            .lock()
+            .unwrap()
+            .retain_thread_cursors(channel_id, thread_ids)
+    }
+
+    /// Returns whether a channel's threads are cleaned up, and whether that includes archived
+    /// ones.
+    pub fn thread_options(&self, channel_id: ChannelId) -> (bool, bool) {
+        self.inner.lock().unwrap().thread_options(channel_id)
+    }
+
+    /// Returns the age below which a channel's messages are always kept, if it has one.
+    pub fn min_age_days(&self, channel_id: ChannelId) -> Option<NonZeroU32> {
+        self.inner.lock().unwrap().min_age_days(channel_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn media(filename: &str, size: u64) -> MediaAttachment {
+        MediaAttachment {
+            url: format!("https://cdn.example/{filename}"),
+            filename: filename.to_string(),
+            size,
+        }
+    }
+
+    #[test]
+    fn extensions_are_matched_with_or_without_a_dot_in_any_case() {
+        for extensions in [["jpg", "PNG"], [".JPG", ".png"]] {
+            let media_backup = MediaBackupConfig {
+                extensions: extensions.map(str::to_string).to_vec(),
+                ..MediaBackupConfig::default()
+            };
+            assert!(media_backup.should_back_up(&media("photo.jpg", 100)));
+            assert!(media_backup.should_back_up(&media("PHOTO.JPG", 100)));
+            assert!(media_backup.should_back_up(&media("screenshot.Png", 100)));
+            assert!(!media_backup.should_back_up(&media("clip.mp4", 100)));
+            assert!(!media_backup.should_back_up(&media("jpg", 100)));
+        }
+    }
+
+    #[test]
+    fn every_extension_is_allowed_without_an_allowlist() {
+        let media_backup = MediaBackupConfig::default();
+        assert!(media_backup.should_back_up(&media("clip.mp4", 100)));
+        assert!(media_backup.should_back_up(&media("no_extension", 100)));
+    }
+
+    #[test]
+    fn attachments_over_the_size_cap_are_not_backed_up() {
+        let media_backup = MediaBackupConfig {
+            max_attachment_bytes: Some(1000),
+            ..MediaBackupConfig::default()
+        };
+        assert!(media_backup.should_back_up(&media("photo.jpg", 1000)));
+        assert!(!media_backup.should_back_up(&media("photo.jpg", 1001)));
+    }
+
+    #[test]
+    fn conflict_behavior_matches_graph_and_config_names() {
+        for (behavior, name) in [
+            (ConflictBehavior::Rename, "rename"),
+            (ConflictBehavior::Replace, "replace"),
+            (ConflictBehavior::Fail, "fail"),
+        ] {
+            assert_eq!(behavior.as_str(), name);
+            assert_eq!(
+                serde_json::to_string(&behavior).unwrap(),
+                format!("\"{name}\"")
+            );
+            let parsed: ConflictBehavior = serde_json::from_str(&format!("\"{name}\"")).unwrap();
+            assert_eq!(parsed.as_str(), name);
+        }
+    }
+
+    fn retention_config() -> Config {
+        Config::parse(
+            r#"
+            schedule_interval_seconds = 3600
+
+            [retention]
+            default_policy_days = 30
+
+            [retention.guild_policy_days]
+            "1" = 14
+
+            [media_backup]
+            download_dir = "media"
+
+            [channels.10]
+            name = "own policy"
+            guild_id = "1"
+            policy_days = 7
+
+            [channels.11]
+            name = "guild policy"
+            guild_id = "1"
+
+            [channels.12]
+            name = "guild without a policy"
+            guild_id = "2"
+
+            [channels.13]
+            name = "enabled before guilds were tracked"
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn policy_days(config: &Config, channel_id: u64) -> u32 {
+        config.channels[&ChannelId::new(channel_id)]
+            .resolve_policy_days(config)
+            .get()
+    }
+
+    #[test]
+    fn channel_policy_beats_guild_policy() {
+        assert_eq!(policy_days(&retention_config(), 10), 7);
+    }
+
+    #[test]
+    fn guild_policy_beats_global_default() {
+        assert_eq!(policy_days(&retention_config(), 11), 14);
+    }
+
+    #[test]
+    fn global_default_applies_without_a_guild_policy() {
+        let config = retention_config();
+        assert_eq!(policy_days(&config, 12), 30);
+        assert_eq!(policy_days(&config, 13), 30);
+    }
+
+    #[test]
+    fn parse_errors_name_the_config_file() {
+        let error = Config::parse("channels = [").unwrap_err();
+        let chain = format!("{error:#}");
+        assert!(chain.contains("config.toml"), "{chain}");
+        // The TOML error is kept, so the line at fault is still reported
+        assert!(error.chain().count() > 1, "{chain}");
+    }
+
+    #[test]
+    fn conflict_behavior_defaults_to_replace() {
+        assert_eq!(ConflictBehavior::default().as_str(), "replace");
+    }
 }
@@ -1,18 +1,25 @@
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
+use metrics_client::{ClientConfig, MetricsClient};
 use poise::samples::register_in_guild;
-use serenity::{Client, all::GatewayIntents};
+use serenity::{
+    Client,
+    all::{GatewayIntents, Http, HttpError, StatusCode},
+};
+use tokio::signal::unix::{SignalKind, signal};
 use tokio::sync::Mutex as TokioMutex;
-use tracing::{error, info};
+use tokio::time::{Instant, sleep};
+use tracing::{error, info, warn};
 
 use crate::{
-    backup::BackupQueue,
+    backup::{BackupQueue, BackupTarget, LocalCopyTarget},
     cancellation::CancellationRegistry,
     cleanup::spawn_worker,
     command::{CommandData, cleanup},
-    config::{Config, ConfigStore},
-    onedrive::{OneDriveClient, TokenStore},
+    config::{BackupTargetConfig, Config, ConfigStore, MetricsConfig, OneDriveConfig},
+    onedrive::{OneDriveAccounts, OneDriveClient, TokenCipher, TokenStore},
 };
 
 mod backup;
@@ -21,41 +28,66 @@ mod cleanup;
 mod command;
 mod config;
 mod media;
+mod metrics;
 mod onedrive;
 
+/// How often shutdown checks whether running work has finished.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
 #[tokio::main]
 async fn main() -> Result<()> {
     shared::init_tracing!()?;
     let bot_config = shared::load_bot_config!()?;
     let config = Config::load()?;
+    let metrics_config = MetricsConfig::from_env()?;
     let backup_worker_config = config.media_backup.worker.clone();
+    let backup_target_config = config.media_backup.target.clone();
+    let archive_daily = config.media_backup.archive_daily;
+    let queue_limit = config.media_backup.queue_limit;
     let onedrive_config = config.onedrive.clone();
     let config_store = ConfigStore::new(config);
-    let backup_queue = Arc::new(Mutex::new(BackupQueue::load()?));
+    config_store.spawn_reloader();
+    let backup_queue = Arc::new(Mutex::new(BackupQueue::load(queue_limit)));
     let cancellation = Arc::new(Mutex::new(CancellationRegistry::new()));
     let intents = GatewayIntents::MESSAGE_CONTENT | GatewayIntents::GUILD_MESSAGES;
 
-    // Initialize OneDrive client if configured
-    let onedrive_client = if let Some(od_config) = onedrive_config {
-        let token_store = Arc::new(TokioMutex::new(TokenStore::new(
-            od_config.client_id.clone(),
-        )));
+    // Heartbeats are sent by the client for as long as it's alive
+    let metrics = metrics_config.map(|metrics| {
+        info!(
+            "Metrics enabled, reporting to {} as {}",
+            metrics.ingest_endpoint, metrics.source
+        );
+        MetricsClient::<metrics::Event>::new(
+            ClientConfig::new(
+                &metrics.ingest_endpoint,
+                &metrics.heartbeat_endpoint,
+                &metrics.source,
+            )
+            .with_heartbeat_interval(metrics.heartbeat_interval),
+        )
+    });
 
-        // Check if we need to authenticate
-        if !token_store.lock().await.has_tokens() {
-            info!("OneDrive tokens not found, starting device code flow...");
-            token_store.lock().await.device_code_flow().await?;
+    // Initialize the backup target if configured
+    let backup_target: Option<Arc<dyn BackupTarget>> = match backup_target_config {
+        BackupTargetConfig::OneDrive => match onedrive_config {
+            Some(onedrive) if !onedrive.accounts().is_empty() => {
+                Some(Arc::new(connect_onedrive(onedrive.accounts()).await?))
+            }
+            _ => {
+                info!("OneDrive not configured, backups will be stored locally only");
+                None
+            }
+        },
+        BackupTargetConfig::LocalCopy { directory } => {
+            info!("Backups will be copied to {}", directory.display());
+            Some(Arc::new(LocalCopyTarget::new(directory)))
         }
-
-        Some(Arc::new(OneDriveClient::new(
-            token_store,
-            od_config.upload_folder,
-        )))
-    } else {
-        info!("OneDrive not configured, backups will be stored locally only");
-        None
     };
 
+    // Cleanup defers deleting backed up messages to the backup worker, so this is settled once
+    // here to match whether the worker runs. Target changes made while running need a restart.
+    let uploads_enabled = backup_target.is_some() && !config_store.dry_run();
+
     let framework = poise::Framework::builder()
         .options(poise::FrameworkOptions {
             commands: vec![cleanup()],
@@ -63,7 +95,9 @@ async fn main() -> Result<()> {
         })
         .setup({
             let config_store = config_store.clone();
+            let backup_queue = Arc::clone(&backup_queue);
             let cancellation = Arc::clone(&cancellation);
+            let metrics = metrics.clone();
 
             move |ctx, ready, framework| {
                 let http = Arc::clone(&ctx.http);
@@ -75,26 +109,36 @@ async fn main() -> Result<()> {
                         register_in_guild(ctx, &framework.options().commands, guild_id.id).await?;
                     }
 
+                    validate_channels(&http, &config_store).await;
+
                     // Spawn the backup worker (only if we have somewhere to back up to)
-                    if let Some(onedrive_client) = onedrive_client {
+                    if let Some(backup_target) = backup_target.filter(|_| uploads_enabled) {
                         backup::spawn_worker(
+                            Arc::clone(&http),
                             Arc::clone(&backup_queue),
                             backup_worker_config,
-                            onedrive_client,
+                            archive_daily,
+                            backup_target,
+                            cancellation.lock().unwrap().shutdown_token(),
                         );
+                    } else if config_store.dry_run() {
+                        info!("Dry run, backups won't be uploaded");
                     }
 
                     // Spawn the cleanup scheduler
                     spawn_worker(
                         Arc::clone(&http),
                         config_store.clone(),
-                        backup_queue,
+                        Arc::clone(&backup_queue),
                         Arc::clone(&cancellation),
+                        metrics,
+                        uploads_enabled,
                     );
 
                     Ok(CommandData {
                         config: config_store,
                         cancellation,
+                        backup_queue,
                     })
                 })
             }
@@ -106,9 +150,160 @@ async fn main() -> Result<()> {
         .await
         .context("Error creating client")?;
 
-    if let Err(why) = client.start().await {
-        error!("Client error: {:?}", why);
+    // Listen for SIGTERM before starting, so a failure shows up at startup
+    let mut terminate = signal(SignalKind::terminate()).context("Failed to listen for SIGTERM")?;
+    let shutdown = async {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    };
+
+    let shard_manager = Arc::clone(&client.shard_manager);
+    tokio::select! {
+        result = client.start() => {
+            if let Err(why) = result {
+                error!("Client error: {:?}", why);
+            }
+        }
+        () = shutdown => {
+            info!("Shutting down...");
+            drain(&cancellation, &backup_queue, config_store.shutdown_grace()).await;
+            shard_manager.shutdown_all().await;
+        }
+    }
+
+    // Flush any buffered metrics before exiting.
+    if let Some(metrics) = metrics {
+        metrics.shutdown().await;
     }
 
     Ok(())
 }
+
+/// Cancel running cleanups and stop new uploads starting, then wait up to `grace` for the work
+/// already in flight to finish.
+async fn drain(
+    cancellation: &Mutex<CancellationRegistry>,
+    backup_queue: &Mutex<BackupQueue>,
+    grace: Duration,
+) {
+    cancellation.lock().unwrap().cancel_all();
+
+    let deadline = Instant::now() + grace;
+    loop {
+        let cleanups = cancellation.lock().unwrap().running_count();
+        let uploads = backup_queue.lock().unwrap().in_progress_count();
+        if cleanups == 0 && uploads == 0 {
+            info!("Running cleanups and uploads finished");
+            break;
+        }
+        if Instant::now() >= deadline {
+            warn!(
+                "Shutting down with {cleanups} cleanups and {uploads} uploads still running, uploads will resume on restart"
+            );
+            break;
+        }
+        sleep(DRAIN_POLL_INTERVAL).await;
+    }
+
+    if let Err(e) = backup_queue.lock().unwrap().flush() {
+        error!("Failed to save backup queue: {e:?}");
+    }
+}
+
+/// Check that every enabled channel still exists, removing those that don't if
+/// `prune_missing_channels` is set. Also fills in the guild of channels enabled without one.
+async fn validate_channels(http: &Http, config_store: &ConfigStore) {
+    let prune = config_store.prune_missing_channels();
+    let mut missing = Vec::new();
+
+    for (channel_id, _) in config_store.enabled_channels() {
+        match http.get_channel(channel_id).await {
+            Ok(channel) => {
+                if let Some(guild_id) = channel.guild().map(|channel| channel.guild_id)
+                    && let Err(e) = config_store.backfill_guild_id(channel_id, guild_id)
+                {
+                    warn!("Failed to record the guild of channel {channel_id}: {e:?}");
+                }
+            }
+            Err(serenity::Error::Http(HttpError::UnsuccessfulRequest(response)))
+                if response.status_code == StatusCode::NOT_FOUND =>
+            {
+                missing.push(channel_id);
+            }
+            // Only a channel Discord says is gone counts as missing
+            Err(e) => warn!("Failed to check channel {channel_id} exists: {e:?}"),
+        }
+    }
+
+    if missing.is_empty() {
+        return;
+    }
+
+    if !prune {
+        warn!(
+            "{} enabled channels no longer exist: {missing:?}. Set prune_missing_channels to remove them",
+            missing.len()
+        );
+        return;
+    }
+
+    let mut removed = Vec::with_capacity(missing.len());
+    for channel_id in missing {
+        match config_store.remove_channel(channel_id) {
+            Ok(()) => removed.push(channel_id),
+            Err(e) => error!("Failed to remove missing channel {channel_id}: {e:?}"),
+        }
+    }
+    info!(
+        "Removed {} channels that no longer exist: {removed:?}",
+        removed.len()
+    );
+}
+
+/// Set up a client for each OneDrive account, authenticating any that have no tokens yet.
+async fn connect_onedrive(accounts: &[OneDriveConfig]) -> Result<OneDriveAccounts> {
+    if TokenCipher::from_env().is_none() {
+        warn!("TOKEN_ENCRYPTION_KEY not set, OneDrive tokens are stored in plaintext");
+    }
+
+    let mut clients = Vec::with_capacity(accounts.len());
+    for (index, od_config) in accounts.iter().enumerate() {
+        // Accounts sharing a name or tokens file would overwrite each other's state
+        if let Some(other) = accounts[..index]
+            .iter()
+            .find(|a| a.name == od_config.name || a.tokens_path == od_config.tokens_path)
+        {
+            bail!(
+                "OneDrive accounts {} and {} must have different names and tokens_path",
+                other.name,
+                od_config.name
+            );
+        }
+
+        let token_store = Arc::new(TokioMutex::new(TokenStore::new(
+            od_config.client_id.clone(),
+            od_config.tokens_path.clone(),
+            TokenCipher::from_env(),
+        )?));
+
+        // Check if we need to authenticate
+        if !token_store.lock().await.has_tokens() {
+            info!(
+                "OneDrive tokens not found for account {}, starting device code flow...",
+                od_config.name
+            );
+            token_store.lock().await.device_code_flow().await?;
+        }
+
+        clients.push(OneDriveClient::new(
+            od_config.name.clone(),
+            token_store,
+            od_config.upload_folder.clone(),
+            od_config.conflict_behavior,
+        ));
+    }
+
+    Ok(OneDriveAccounts::new(clients))
+}
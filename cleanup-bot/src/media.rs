@@ -2,4 +2,4 @@ pub mod attachment;
 pub mod downloader;
 
 pub use attachment::*;
-pub use downloader::MediaDownloader;
+pub use downloader::{DownloadResult, MediaDownloader, dir_size};
@@ -5,6 +5,8 @@ use serenity::all::Attachment;
 pub struct MediaAttachment {
     pub url: String,
     pub filename: String,
+    /// Size in bytes, as reported by Discord.
+    pub size: u64,
 }
 
 pub trait AttachmentsExt {
@@ -29,6 +31,7 @@ impl AttachmentsExt for Vec<Attachment> {
                     Some(MediaAttachment {
                         url: a.url.clone(),
                         filename: a.filename.clone(),
+                        size: a.size.into(),
                     })
                 } else {
                     None
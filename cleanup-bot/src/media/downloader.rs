@@ -1,3 +1,4 @@
+use std::io;
 use std::path::{Path, PathBuf};
 
 use anyhow::{Context, Result};
@@ -10,6 +11,27 @@ use tracing::{debug, info};
 
 use crate::media::MediaAttachment;
 
+/// Total size in bytes of the files under a directory, or 0 if it doesn't exist yet.
+pub fn dir_size(path: &Path) -> io::Result<u64> {
+    let entries = match std::fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    let mut size = 0;
+    for entry in entries {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+    Ok(size)
+}
+
 /// Downloads media attachments to the local filesystem.
 pub struct MediaDownloader {
     client: Client,
@@ -114,3 +136,34 @@ impl MediaDownloader {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dir_size_counts_nested_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let nested = dir.path().join("2024-01-01").join("inner");
+        std::fs::create_dir_all(&nested).unwrap();
+        std::fs::write(dir.path().join("a.bin"), [0; 10]).unwrap();
+        std::fs::write(dir.path().join("2024-01-01").join("b.bin"), [0; 20]).unwrap();
+        std::fs::write(nested.join("c.bin"), [0; 30]).unwrap();
+
+        assert_eq!(dir_size(dir.path()).unwrap(), 60);
+    }
+
+    #[test]
+    fn dir_size_of_empty_dir_is_zero() {
+        let dir = tempfile::tempdir().unwrap();
+
+        assert_eq!(dir_size(dir.path()).unwrap(), 0);
+    }
+
+    #[test]
+    fn dir_size_of_missing_dir_is_zero() {
+        let dir = tempfile::tempdir().unwrap();
+
+        assert_eq!(dir_size(&dir.path().join("missing")).unwrap(), 0);
+    }
+}
@@ -0,0 +1,29 @@
+//! Metric event ids, label keys, and value names reported by the bot.
+
+/// The complete set of metric event ids the cleanup bot emits.
+#[derive(Debug, Clone, Copy)]
+pub enum Event {
+    /// A cleanup pass over one channel finished.
+    ChannelCleaned,
+}
+
+impl From<Event> for String {
+    fn from(event: Event) -> String {
+        match event {
+            Event::ChannelCleaned => "channel_cleaned",
+        }
+        .to_owned()
+    }
+}
+
+/// String label keys attached to events.
+pub mod label {
+    pub const CHANNEL_ID: &str = "channel_id";
+}
+
+/// Numeric value names attached to events.
+pub mod value {
+    pub const MESSAGES_DELETED: &str = "messages_deleted";
+    pub const MEDIA_BACKED_UP: &str = "media_backed_up";
+    pub const PAGINATION_ROUNDS: &str = "pagination_rounds";
+}
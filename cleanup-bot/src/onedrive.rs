@@ -1,8 +1,13 @@
+mod accounts;
 mod auth;
 mod client;
+mod crypto;
+mod quick_xor;
 
+pub use accounts::OneDriveAccounts;
 pub use auth::TokenStore;
 pub use client::OneDriveClient;
+pub use crypto::TokenCipher;
 
 use thiserror::Error;
 
@@ -0,0 +1,146 @@
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serenity::async_trait;
+use tracing::{info, warn};
+
+use super::client::OneDriveClient;
+use crate::backup::{BackupError, BackupTarget, OnProgress, UploadProgress};
+
+/// Several OneDrive accounts sharing the backups between them, so one account's quota isn't a
+/// limit.
+pub struct OneDriveAccounts {
+    clients: Vec<OneDriveClient>,
+    next: AtomicUsize,
+}
+
+impl OneDriveAccounts {
+    /// Takes at least one client.
+    pub fn new(clients: Vec<OneDriveClient>) -> Self {
+        assert!(
+            !clients.is_empty(),
+            "at least one OneDrive account is needed"
+        );
+        Self {
+            clients,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the next account in round-robin order.
+    pub fn select_target(&self) -> &OneDriveClient {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.clients.len();
+        &self.clients[index]
+    }
+
+    /// Returns the next account in round-robin order with room for `size` bytes. If none reports
+    /// enough, falls back to plain round-robin and lets the upload fail.
+    async fn select_with_room(&self, size: u64) -> &OneDriveClient {
+        if self.clients.len() == 1 {
+            return &self.clients[0];
+        }
+
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % self.clients.len();
+        let mut quotas = Vec::with_capacity(self.clients.len());
+        for offset in 0..self.clients.len() {
+            let index = (start + offset) % self.clients.len();
+            let client = &self.clients[index];
+            match client.remaining_quota().await {
+                Ok(remaining) => quotas.push((index, remaining)),
+                Err(e) => warn!(
+                    "Failed to check quota of OneDrive account {}: {e}",
+                    client.account()
+                ),
+            }
+        }
+
+        match first_with_room(&quotas, size) {
+            Some(index) => &self.clients[index],
+            None => {
+                warn!("No OneDrive account reports room for {size} bytes");
+                self.select_target()
+            }
+        }
+    }
+
+    fn find(&self, account: &str) -> Option<&OneDriveClient> {
+        self.clients.iter().find(|c| c.account() == account)
+    }
+}
+
+/// Picks the first of `quotas`, pairs of an account and its remaining bytes in the order to try
+/// them, with room for `size` bytes. Accounts that don't report a quota are assumed to have room.
+fn first_with_room(quotas: &[(usize, Option<u64>)], size: u64) -> Option<usize> {
+    quotas
+        .iter()
+        .find(|(_, remaining)| remaining.is_none_or(|remaining| remaining >= size))
+        .map(|&(account, _)| account)
+}
+
+#[async_trait]
+impl BackupTarget for OneDriveAccounts {
+    async fn upload_file(
+        &self,
+        path: &Path,
+        progress: &UploadProgress,
+        on_progress: &OnProgress<'_>,
+    ) -> Result<(), BackupError> {
+        // Retries stay on the account an earlier attempt picked
+        if let Some(client) = progress.account.as_deref().and_then(|a| self.find(a)) {
+            return BackupTarget::upload_file(client, path, progress, on_progress).await;
+        }
+
+        if let Some(account) = &progress.account {
+            info!("OneDrive account {account} is no longer configured, picking another");
+        }
+
+        let size = tokio::fs::metadata(path).await?.len();
+        let client = self.select_with_room(size).await;
+        let progress = UploadProgress {
+            account: Some(client.account().to_string()),
+            upload_session: None,
+        };
+        on_progress(&progress);
+
+        BackupTarget::upload_file(client, path, &progress, on_progress).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_the_first_account_with_room() {
+        let quotas = [(2, Some(10)), (0, Some(500)), (1, Some(1000))];
+
+        assert_eq!(first_with_room(&quotas, 100), Some(0));
+    }
+
+    #[test]
+    fn ties_go_to_the_first_account_in_order() {
+        let quotas = [(1, Some(500)), (0, Some(500))];
+
+        assert_eq!(first_with_room(&quotas, 100), Some(1));
+    }
+
+    #[test]
+    fn exactly_enough_room_is_enough() {
+        assert_eq!(first_with_room(&[(0, Some(100))], 100), Some(0));
+    }
+
+    #[test]
+    fn unknown_quota_counts_as_room() {
+        let quotas = [(0, Some(10)), (1, None), (2, Some(1000))];
+
+        assert_eq!(first_with_room(&quotas, 100), Some(1));
+    }
+
+    #[test]
+    fn no_account_when_all_are_full() {
+        let quotas = [(0, Some(10)), (1, Some(99))];
+
+        assert_eq!(first_with_room(&quotas, 100), None);
+        assert_eq!(first_with_room(&[], 100), None);
+    }
+}
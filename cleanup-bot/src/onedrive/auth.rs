@@ -1,17 +1,22 @@
-use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 use std::time::Duration;
+use std::{env, fs};
 
 use chrono::{DateTime, Utc};
+use qrcode::QrCode;
+use qrcode::render::unicode::Dense1x2;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use tracing::{debug, info, warn};
 
 use super::OneDriveError;
+use super::crypto::{EncryptedTokens, TokenCipher};
 
-const TOKENS_PATH: &str = "./onedrive_tokens.toml";
 const AUTH_URL: &str = "https://login.microsoftonline.com/consumers/oauth2/v2.0";
 const SCOPES: &str = "Files.ReadWrite offline_access";
+/// Set to `1` to open the verification page in a browser during device code flow.
+const OPEN_BROWSER_ENV: &str = "OPEN_BROWSER";
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct StoredTokens {
@@ -25,6 +30,8 @@ struct DeviceCodeResponse {
     device_code: String,
     user_code: String,
     verification_uri: String,
+    /// The verification page with the code filled in, when the server provides one
+    verification_uri_complete: Option<String>,
     expires_in: u64,
     interval: u64,
 }
@@ -45,32 +52,63 @@ struct ErrorResponse {
 pub struct TokenStore {
     client_id: String,
     http: Client,
+    /// Base URL of the OAuth endpoints, normally [`AUTH_URL`]
+    auth_url: String,
+    tokens_path: PathBuf,
+    /// Encrypts the tokens file when set, otherwise it's stored as plaintext
+    cipher: Option<TokenCipher>,
     tokens: Option<StoredTokens>,
 }
 
 impl TokenStore {
-    pub fn new(client_id: String) -> Self {
-        Self {
+    pub fn new(
+        client_id: String,
+        tokens_path: PathBuf,
+        cipher: Option<TokenCipher>,
+    ) -> Result<Self, OneDriveError> {
+        Ok(Self {
             client_id,
             http: Client::new(),
-            tokens: Self::load_tokens(),
-        }
+            auth_url: AUTH_URL.to_string(),
+            tokens: Self::load_tokens(&tokens_path, cipher.as_ref())?,
+            tokens_path,
+            cipher,
+        })
     }
 
-    fn load_tokens() -> Option<StoredTokens> {
-        let path = Path::new(TOKENS_PATH);
-
-        match fs::read_to_string(path) {
-            Ok(content) => match toml::from_str(&content) {
-                Ok(tokens) => Some(tokens),
-                Err(e) => {
-                    warn!("Failed to parse tokens file: {e}");
-                    None
-                }
-            },
+    /// Load tokens, decrypting them if the file is encrypted.
+    ///
+    /// An unreadable file just means re-authenticating, but an encrypted one that can't be
+    /// decrypted is an error, so a missing or wrong key doesn't get it overwritten.
+    fn load_tokens(
+        path: &Path,
+        cipher: Option<&TokenCipher>,
+    ) -> Result<Option<StoredTokens>, OneDriveError> {
+        let mut content = match fs::read_to_string(path) {
+            Ok(content) => content,
             Err(e) => {
                 warn!("Failed to read tokens file: {e}");
-                None
+                return Ok(None);
+            }
+        };
+
+        if let Ok(encrypted) = toml::from_str::<EncryptedTokens>(&content) {
+            let Some(cipher) = cipher else {
+                return Err(OneDriveError::TokenStorage(
+                    "Tokens file is encrypted, but TOKEN_ENCRYPTION_KEY isn't set".to_string(),
+                ));
+            };
+            content = String::from_utf8(cipher.decrypt(&encrypted)?)
+                .map_err(|e| OneDriveError::TokenStorage(e.to_string()))?;
+        } else if cipher.is_some() {
+            info!("Tokens file isn't encrypted yet, it will be on the next save");
+        }
+
+        match toml::from_str(&content) {
+            Ok(tokens) => Ok(Some(tokens)),
+            Err(e) => {
+                warn!("Failed to parse tokens file: {e}");
+                Ok(None)
             }
         }
     }
@@ -80,10 +118,17 @@ impl TokenStore {
             return Ok(());
         };
 
-        let content = toml::to_string_pretty(tokens)
+        let mut content = toml::to_string_pretty(tokens)
             .map_err(|e| OneDriveError::TokenStorage(e.to_string()))?;
+        if let Some(cipher) = &self.cipher {
+            content = toml::to_string_pretty(&cipher.encrypt(content.as_bytes())?)
+                .map_err(|e| OneDriveError::TokenStorage(e.to_string()))?;
+        }
 
-        fs::write(TOKENS_PATH, content)?;
+        if let Some(parent) = self.tokens_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&self.tokens_path, content)?;
         Ok(())
     }
 
@@ -92,6 +137,10 @@ impl TokenStore {
     }
 
     /// Get a valid access token, refreshing if necessary.
+    ///
+    /// Callers share the store behind a `tokio::sync::Mutex` and hold the lock until this returns,
+    /// so only one refresh runs at a time. Callers queued behind it see the refreshed token and
+    /// don't refresh again.
     pub async fn get_valid_token(&mut self) -> Result<String, OneDriveError> {
         let Some(tokens) = &self.tokens else {
             return Err(OneDriveError::Auth("No tokens available".to_string()));
@@ -114,7 +163,7 @@ impl TokenStore {
         // Request device code
         let resp = self
             .http
-            .post(format!("{AUTH_URL}/devicecode"))
+            .post(format!("{}/devicecode", self.auth_url))
             .form(&[
                 ("client_id", &self.client_id),
                 ("scope", &SCOPES.to_string()),
@@ -135,6 +184,14 @@ impl TokenStore {
             "To authenticate OneDrive, visit {} and enter code: {}",
             device_code.verification_uri, device_code.user_code
         );
+        let verification_uri = device_code
+            .verification_uri_complete
+            .as_deref()
+            .unwrap_or(&device_code.verification_uri);
+        print_qr_code(verification_uri);
+        if env::var(OPEN_BROWSER_ENV).is_ok_and(|value| value == "1") {
+            open_browser(verification_uri);
+        }
 
         // Poll for token
         let poll_interval = Duration::from_secs(device_code.interval);
@@ -149,7 +206,7 @@ impl TokenStore {
 
             let resp = self
                 .http
-                .post(format!("{AUTH_URL}/token"))
+                .post(format!("{}/token", self.auth_url))
                 .form(&[
                     ("client_id", self.client_id.as_str()),
                     ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
@@ -199,7 +256,7 @@ impl TokenStore {
 
         let resp = self
             .http
-            .post(format!("{AUTH_URL}/token"))
+            .post(format!("{}/token", self.auth_url))
             .form(&[
                 ("client_id", self.client_id.as_str()),
                 ("grant_type", "refresh_token"),
@@ -227,3 +284,117 @@ impl TokenStore {
         Ok(())
     }
 }
+
+/// Print a QR code of the verification page to stdout, for scanning with a phone on a headless
+/// box.
+fn print_qr_code(url: &str) {
+    match QrCode::new(url) {
+        Ok(code) => {
+            let qr = code
+                .render::<Dense1x2>()
+                .dark_color(Dense1x2::Light)
+                .light_color(Dense1x2::Dark)
+                .build();
+            println!("{qr}");
+        }
+        Err(e) => warn!("Failed to render QR code: {e}"),
+    }
+}
+
+/// Try to open the verification page in the default browser.
+fn open_browser(url: &str) {
+    let opener = if cfg!(target_os = "macos") {
+        "open"
+    } else {
+        "xdg-open"
+    };
+
+    if let Err(e) = Command::new(opener).arg(url).spawn() {
+        warn!("Failed to open {url} with {opener}: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    use tokio::sync::Mutex;
+
+    use super::*;
+
+    /// Serves token refreshes on a local port, counting them. Returns the base URL and the count.
+    fn token_server() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let refreshes = Arc::new(AtomicUsize::new(0));
+        let count = Arc::clone(&refreshes);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(&stream);
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" || line.is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':')
+                        && name.eq_ignore_ascii_case("content-length")
+                    {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+                reader.read_exact(&mut vec![0; content_length]).unwrap();
+
+                let n = count.fetch_add(1, Ordering::SeqCst) + 1;
+                let body = format!(
+                    r#"{{"access_token":"access-{n}","refresh_token":"refresh-{n}","expires_in":3600}}"#
+                );
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                )
+                .unwrap();
+            }
+        });
+        (url, refreshes)
+    }
+
+    #[tokio::test]
+    async fn concurrent_callers_share_one_refresh() {
+        let dir = tempfile::tempdir().unwrap();
+        let (auth_url, refreshes) = token_server();
+        let store = Arc::new(Mutex::new(TokenStore {
+            client_id: "client".to_string(),
+            http: Client::new(),
+            auth_url,
+            tokens_path: dir.path().join("tokens.toml"),
+            cipher: None,
+            tokens: Some(StoredTokens {
+                access_token: "access-0".to_string(),
+                refresh_token: "refresh-0".to_string(),
+                // Within the expiry buffer
+                expires_at: Utc::now() + chrono::Duration::minutes(1),
+            }),
+        }));
+
+        let callers: Vec<_> = (0..8)
+            .map(|_| {
+                let store = Arc::clone(&store);
+                tokio::spawn(async move { store.lock().await.get_valid_token().await })
+            })
+            .collect();
+        for caller in callers {
+            assert_eq!(caller.await.unwrap().unwrap(), "access-1");
+        }
+
+        assert_eq!(refreshes.load(Ordering::SeqCst), 1);
+    }
+}
@@ -1,14 +1,20 @@
+use std::io::SeekFrom;
 use std::path::Path;
 use std::sync::Arc;
 
 use chrono::{Datelike, NaiveDate, Utc};
 use reqwest::Client;
 use serde::Deserialize;
+use serenity::async_trait;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use tokio::sync::Mutex;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 
 use super::OneDriveError;
 use super::auth::TokenStore;
+use super::quick_xor::QuickXorHash;
+use crate::backup::{BackupError, BackupTarget, OnProgress, UploadProgress};
+use crate::config::ConflictBehavior;
 
 const GRAPH_API: &str = "https://graph.microsoft.com/v1.0";
 const SIMPLE_UPLOAD_LIMIT: u64 = 4 * 1024 * 1024; // 4MB
@@ -20,23 +26,104 @@ struct UploadSession {
     upload_url: String,
 }
 
+#[derive(Deserialize)]
+struct UploadSessionStatus {
+    /// Byte ranges still to upload, e.g. `["26214400-"]`.
+    #[serde(rename = "nextExpectedRanges")]
+    next_expected_ranges: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct DriveItem {
+    name: String,
+    size: u64,
+    file: Option<FileFacet>,
+}
+
+#[derive(Deserialize)]
+struct FileFacet {
+    #[serde(default)]
+    hashes: Option<Hashes>,
+}
+
+#[derive(Deserialize)]
+struct Hashes {
+    #[serde(rename = "quickXorHash")]
+    quick_xor_hash: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Drive {
+    quota: Option<Quota>,
+}
+
+#[derive(Deserialize)]
+struct Quota {
+    remaining: Option<u64>,
+}
+
 pub struct OneDriveClient {
+    /// Name of the account, from its config
+    account: String,
     http: Client,
     token_store: Arc<Mutex<TokenStore>>,
     upload_folder: String,
+    conflict_behavior: ConflictBehavior,
 }
 
 impl OneDriveClient {
-    pub fn new(token_store: Arc<Mutex<TokenStore>>, upload_folder: String) -> Self {
+    pub fn new(
+        account: String,
+        token_store: Arc<Mutex<TokenStore>>,
+        upload_folder: String,
+        conflict_behavior: ConflictBehavior,
+    ) -> Self {
         Self {
+            account,
             http: Client::new(),
             token_store,
             upload_folder,
+            conflict_behavior,
         }
     }
 
+    pub fn account(&self) -> &str {
+        &self.account
+    }
+
+    /// Returns the bytes left in the drive's quota, if OneDrive reports it.
+    pub async fn remaining_quota(&self) -> Result<Option<u64>, OneDriveError> {
+        let token = self.token_store.lock().await.get_valid_token().await?;
+        let resp = self
+            .http
+            .get(format!("{GRAPH_API}/me/drive"))
+            .bearer_auth(&token)
+            .send()
+            .await?;
+
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let body = resp.text().await.unwrap_or_default();
+            return Err(OneDriveError::Upload(format!(
+                "Failed to fetch drive quota: {status}: {body}"
+            )));
+        }
+
+        let drive: Drive = resp.json().await?;
+        Ok(drive.quota.and_then(|q| q.remaining))
+    }
+
     /// Upload a file to OneDrive. Automatically uses simple or resumable upload based on file size.
-    pub async fn upload_file(&self, local_path: &Path) -> Result<(), OneDriveError> {
+    ///
+    /// `upload_session` is the session URL left by an earlier attempt at a resumable upload, which
+    /// is continued if it is still live. When a new session is created, `on_session` is called with
+    /// its URL so it can be saved for the next attempt.
+    pub async fn upload_file(
+        &self,
+        local_path: &Path,
+        upload_session: Option<&str>,
+        on_session: impl FnOnce(&str),
+    ) -> Result<(), OneDriveError> {
         let remote_path = self.build_remote_path(local_path);
         let metadata = tokio::fs::metadata(local_path).await?;
         let file_size = metadata.len();
@@ -46,12 +133,78 @@ impl OneDriveClient {
             local_path.display(),
         );
 
-        if file_size < SIMPLE_UPLOAD_LIMIT {
-            self.simple_upload(local_path, &remote_path).await
+        let item = if file_size < SIMPLE_UPLOAD_LIMIT {
+            self.simple_upload(local_path, &remote_path).await?
         } else {
-            self.resumable_upload(local_path, &remote_path, file_size)
-                .await
+            self.resumable_upload(
+                local_path,
+                &remote_path,
+                file_size,
+                upload_session,
+                on_session,
+            )
+            .await?
+        };
+
+        self.verify_upload(item, local_path, &remote_path, file_size)
+            .await
+    }
+
+    /// Fetch the drive item at a path.
+    async fn fetch_item(&self, remote_path: &str) -> Result<DriveItem, OneDriveError> {
+        let token = self.token_store.lock().await.get_valid_token().await?;
+        let url = format!("{GRAPH_API}/me/drive/root:{remote_path}");
+
+        let resp = self.http.get(&url).bearer_auth(&token).send().await?;
+
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let body = resp.text().await.unwrap_or_default();
+            return Err(OneDriveError::Upload(format!(
+                "Failed to fetch uploaded item: {status}: {body}"
+            )));
+        }
+
+        Ok(resp.json().await?)
+    }
+
+    /// Check the uploaded item's size and content hash against the local file.
+    async fn verify_upload(
+        &self,
+        item: DriveItem,
+        local_path: &Path,
+        remote_path: &str,
+        file_size: u64,
+    ) -> Result<(), OneDriveError> {
+        if !remote_path.ends_with(&format!("/{}", item.name)) {
+            info!("{remote_path} already existed, uploaded as {}", item.name);
         }
+
+        if item.size != file_size {
+            return Err(OneDriveError::Upload(format!(
+                "Uploaded size {} doesn't match local size {file_size}",
+                item.size
+            )));
+        }
+
+        let Some(remote_hash) = item
+            .file
+            .and_then(|f| f.hashes)
+            .and_then(|h| h.quick_xor_hash)
+        else {
+            warn!("OneDrive reported no hash for {remote_path}, only its size was verified");
+            return Ok(());
+        };
+
+        let local_hash = quick_xor_hash(local_path).await?;
+        if remote_hash != local_hash {
+            return Err(OneDriveError::Upload(format!(
+                "Uploaded hash {remote_hash} doesn't match local hash {local_hash}"
+            )));
+        }
+
+        debug!("Verified upload of {remote_path}");
+        Ok(())
     }
 
     /// Build the remote path with date-based organization.
@@ -80,16 +233,19 @@ impl OneDriveClient {
         )
     }
 
-    /// Simple upload for files < 4MB.
+    /// Simple upload for files < 4MB, small enough to read into memory whole.
     async fn simple_upload(
         &self,
         local_path: &Path,
         remote_path: &str,
-    ) -> Result<(), OneDriveError> {
+    ) -> Result<DriveItem, OneDriveError> {
         let token = self.token_store.lock().await.get_valid_token().await?;
         let content = tokio::fs::read(local_path).await?;
 
-        let url = format!("{GRAPH_API}/me/drive/root:{remote_path}:/content");
+        let url = format!(
+            "{GRAPH_API}/me/drive/root:{remote_path}:/content?@microsoft.graph.conflictBehavior={}",
+            self.conflict_behavior.as_str()
+        );
 
         let resp = self
             .http
@@ -109,7 +265,7 @@ impl OneDriveClient {
         }
 
         debug!("Simple upload completed for {remote_path}");
-        Ok(())
+        Ok(resp.json().await?)
     }
 
     /// Resumable upload for files >= 4MB.
@@ -117,15 +273,88 @@ impl OneDriveClient {
         &self,
         local_path: &Path,
         remote_path: &str,
-        _file_size: u64,
-    ) -> Result<(), OneDriveError> {
+        file_size: u64,
+        upload_session: Option<&str>,
+        on_session: impl FnOnce(&str),
+    ) -> Result<DriveItem, OneDriveError> {
+        // Continue an earlier attempt if its session is still live
+        let resumed = match upload_session {
+            Some(upload_url) => match self.query_upload_status(upload_url).await {
+                Ok(offset) => {
+                    info!("Resuming upload of {remote_path} from byte {offset}");
+                    Some((upload_url.to_string(), offset))
+                }
+                Err(e) => {
+                    warn!("Can't resume upload of {remote_path}, starting over: {e}");
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let (upload_url, mut start) = match resumed {
+            Some(resumed) => resumed,
+            None => {
+                let upload_url = self.create_upload_session(remote_path).await?;
+                on_session(&upload_url);
+                (upload_url, 0)
+            }
+        };
+
+        // Read and upload one chunk at a time, so memory use doesn't grow with the file
+        let mut file = tokio::fs::File::open(local_path).await?;
+        file.seek(SeekFrom::Start(start)).await?;
+        let mut chunk_num = 0;
+        let mut item = None;
+
+        while start < file_size {
+            let (chunk_len, content_range) = next_chunk(start, file_size);
+            let mut chunk = vec![0; chunk_len as usize];
+            file.read_exact(&mut chunk).await?;
+            chunk_num += 1;
+
+            debug!("Uploading chunk {chunk_num}: {content_range}");
+
+            let resp = self
+                .http
+                .put(&upload_url)
+                .header("Content-Range", &content_range)
+                .body(chunk)
+                .send()
+                .await?;
+
+            if !resp.status().is_success() && resp.status().as_u16() != 202 {
+                let status = resp.status();
+                let body = resp.text().await.unwrap_or_default();
+                return Err(OneDriveError::Upload(format!(
+                    "Chunk upload failed: {status}: {body}"
+                )));
+            }
+
+            // The final chunk's response is the uploaded item
+            if resp.status().as_u16() != 202 {
+                item = Some(resp.json().await?);
+            }
+
+            start += chunk_len;
+        }
+
+        debug!("Resumable upload completed for {remote_path}");
+        match item {
+            Some(item) => Ok(item),
+            None => self.fetch_item(remote_path).await,
+        }
+    }
+
+    /// Create a resumable upload session, returning its upload URL.
+    async fn create_upload_session(&self, remote_path: &str) -> Result<String, OneDriveError> {
         let token = self.token_store.lock().await.get_valid_token().await?;
 
         // Create upload session
         let url = format!("{GRAPH_API}/me/drive/root:{remote_path}:/createUploadSession");
         let body = serde_json::json!({
             "item": {
-                "@microsoft.graph.conflictBehavior": "replace"
+                "@microsoft.graph.conflictBehavior": self.conflict_behavior.as_str()
             }
         });
 
@@ -147,36 +376,153 @@ impl OneDriveClient {
 
         let session: UploadSession = resp.json().await?;
         debug!("Created upload session for {remote_path}");
+        Ok(session.upload_url)
+    }
 
-        // Read file and upload in chunks
-        let content = tokio::fs::read(local_path).await?;
-        let total_size = content.len();
+    /// Ask an upload session which byte it expects next.
+    pub async fn query_upload_status(&self, upload_url: &str) -> Result<u64, OneDriveError> {
+        // Upload URLs are pre-authenticated, so no token is sent
+        let resp = self.http.get(upload_url).send().await?;
 
-        for (chunk_num, chunk) in content.chunks(CHUNK_SIZE).enumerate() {
-            let start = chunk_num * CHUNK_SIZE;
-            let end = start + chunk.len() - 1;
-            let content_range = format!("bytes {start}-{end}/{total_size}");
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let body = resp.text().await.unwrap_or_default();
+            return Err(OneDriveError::Upload(format!(
+                "Failed to query upload session: {status}: {body}"
+            )));
+        }
 
-            debug!("Uploading chunk {}: {content_range}", chunk_num + 1);
+        let status: UploadSessionStatus = resp.json().await?;
+        status
+            .next_expected_ranges
+            .first()
+            .and_then(|range| range.split('-').next())
+            .and_then(|start| start.parse().ok())
+            .ok_or_else(|| {
+                OneDriveError::Upload(format!(
+                    "Unexpected upload session ranges: {:?}",
+                    status.next_expected_ranges
+                ))
+            })
+    }
+}
 
-            let resp = self
-                .http
-                .put(&session.upload_url)
-                .header("Content-Range", &content_range)
-                .body(chunk.to_vec())
-                .send()
-                .await?;
+/// The length and `Content-Range` header of the resumable upload chunk starting at byte `start`
+/// of a `file_size` byte file. The final chunk is whatever is left.
+fn next_chunk(start: u64, file_size: u64) -> (u64, String) {
+    let chunk_len = (file_size - start).min(CHUNK_SIZE as u64);
+    let end = start + chunk_len - 1;
+    (chunk_len, format!("bytes {start}-{end}/{file_size}"))
+}
 
-            if !resp.status().is_success() && resp.status().as_u16() != 202 {
-                let status = resp.status();
-                let body = resp.text().await.unwrap_or_default();
-                return Err(OneDriveError::Upload(format!(
-                    "Chunk upload failed: {status}: {body}"
-                )));
-            }
+/// Compute a file's QuickXorHash, reading it a chunk at a time.
+async fn quick_xor_hash(path: &Path) -> Result<String, OneDriveError> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hash = QuickXorHash::new();
+    let mut buf = vec![0; CHUNK_SIZE];
+
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            break;
         }
+        hash.update(&buf[..read]);
+    }
 
-        debug!("Resumable upload completed for {remote_path}");
-        Ok(())
+    Ok(hash.finalize())
+}
+
+#[async_trait]
+impl BackupTarget for OneDriveClient {
+    async fn upload_file(
+        &self,
+        path: &Path,
+        progress: &UploadProgress,
+        on_progress: &OnProgress<'_>,
+    ) -> Result<(), BackupError> {
+        let on_session = |upload_url: &str| {
+            on_progress(&UploadProgress {
+                account: Some(self.account.clone()),
+                upload_session: Some(upload_url.to_string()),
+            })
+        };
+        let upload_session = progress.upload_session.as_deref();
+        Ok(OneDriveClient::upload_file(self, path, upload_session, on_session).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn client(upload_folder: &str) -> OneDriveClient {
+        // No tokens file, so the store starts out empty
+        let token_store =
+            TokenStore::new("client".to_string(), PathBuf::from("/nonexistent"), None).unwrap();
+        OneDriveClient::new(
+            "default".to_string(),
+            Arc::new(Mutex::new(token_store)),
+            upload_folder.to_string(),
+            ConflictBehavior::default(),
+        )
+    }
+
+    #[test]
+    fn remote_path_is_organized_by_the_date_dir() {
+        let path = Path::new("media_backups/2024-03-07/123_photo.jpg");
+        assert_eq!(
+            client("/Discord").build_remote_path(path),
+            "/Discord/2024/03/07/123_photo.jpg"
+        );
+    }
+
+    #[test]
+    fn remote_path_ignores_a_trailing_slash_on_the_upload_folder() {
+        let path = Path::new("media_backups/2024-03-07/123_photo.jpg");
+        assert_eq!(
+            client("/Discord/").build_remote_path(path),
+            "/Discord/2024/03/07/123_photo.jpg"
+        );
+    }
+
+    #[test]
+    fn remote_path_falls_back_to_today_without_a_date_dir() {
+        let today = Utc::now().format("%Y/%m/%d");
+        let path = Path::new("media_backups/not-a-date/123_photo.jpg");
+        assert_eq!(
+            client("/Discord").build_remote_path(path),
+            format!("/Discord/{today}/123_photo.jpg")
+        );
+    }
+
+    #[test]
+    fn chunks_cover_the_file_with_a_short_final_chunk() {
+        let file_size = 25 * 1024 * 1024;
+        let mut ranges = Vec::new();
+        let mut start = 0;
+        while start < file_size {
+            let (chunk_len, content_range) = next_chunk(start, file_size);
+            ranges.push(content_range);
+            start += chunk_len;
+        }
+
+        assert_eq!(
+            ranges,
+            [
+                "bytes 0-10485759/26214400",
+                "bytes 10485760-20971519/26214400",
+                "bytes 20971520-26214399/26214400",
+            ]
+        );
+    }
+
+    #[test]
+    fn resumed_chunks_start_at_the_offset() {
+        assert_eq!(
+            next_chunk(20971520, 26214400),
+            (5242880, "bytes 20971520-26214399/26214400".to_string())
+        );
     }
 }
@@ -0,0 +1,105 @@
+use std::env;
+use std::num::NonZeroU32;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use ring::aead::{Aad, CHACHA20_POLY1305, LessSafeKey, NONCE_LEN, Nonce, UnboundKey};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+
+use super::OneDriveError;
+
+const KEY_ENV: &str = "TOKEN_ENCRYPTION_KEY";
+const SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+const PBKDF2_ITERATIONS: NonZeroU32 = NonZeroU32::new(100_000).unwrap();
+
+/// Encrypted form of the tokens file. All fields are base64.
+#[derive(Serialize, Deserialize)]
+pub struct EncryptedTokens {
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Encrypts the tokens file with ChaCha20-Poly1305, using a key derived from
+/// `TOKEN_ENCRYPTION_KEY` with PBKDF2.
+pub struct TokenCipher {
+    passphrase: String,
+    rng: SystemRandom,
+}
+
+impl TokenCipher {
+    /// Returns a cipher if `TOKEN_ENCRYPTION_KEY` is set.
+    pub fn from_env() -> Option<Self> {
+        env::var(KEY_ENV)
+            .ok()
+            .filter(|passphrase| !passphrase.is_empty())
+            .map(|passphrase| Self {
+                passphrase,
+                rng: SystemRandom::new(),
+            })
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<EncryptedTokens, OneDriveError> {
+        // A fresh salt and nonce on every save, so no nonce is ever reused under a key
+        let mut salt = [0u8; SALT_LEN];
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng
+            .fill(&mut salt)
+            .and_then(|()| self.rng.fill(&mut nonce))
+            .map_err(|_| storage_error("failed to generate random bytes"))?;
+
+        let mut in_out = plaintext.to_vec();
+        self.key(&salt)?
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::empty(),
+                &mut in_out,
+            )
+            .map_err(|_| storage_error("failed to encrypt tokens"))?;
+
+        Ok(EncryptedTokens {
+            salt: STANDARD.encode(salt),
+            nonce: STANDARD.encode(nonce),
+            ciphertext: STANDARD.encode(in_out),
+        })
+    }
+
+    pub fn decrypt(&self, encrypted: &EncryptedTokens) -> Result<Vec<u8>, OneDriveError> {
+        let decode = |field: &str| {
+            STANDARD
+                .decode(field)
+                .map_err(|e| storage_error(&format!("invalid encrypted tokens: {e}")))
+        };
+        let salt = decode(&encrypted.salt)?;
+        let nonce = Nonce::try_assume_unique_for_key(&decode(&encrypted.nonce)?)
+            .map_err(|_| storage_error("invalid encrypted tokens nonce"))?;
+        let mut in_out = decode(&encrypted.ciphertext)?;
+
+        let plaintext = self
+            .key(&salt)?
+            .open_in_place(nonce, Aad::empty(), &mut in_out)
+            .map_err(|_| storage_error(&format!("failed to decrypt tokens, check {KEY_ENV}")))?;
+        Ok(plaintext.to_vec())
+    }
+
+    fn key(&self, salt: &[u8]) -> Result<LessSafeKey, OneDriveError> {
+        let mut key = [0u8; KEY_LEN];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            PBKDF2_ITERATIONS,
+            salt,
+            self.passphrase.as_bytes(),
+            &mut key,
+        );
+        let key = UnboundKey::new(&CHACHA20_POLY1305, &key)
+            .map_err(|_| storage_error("failed to create encryption key"))?;
+        Ok(LessSafeKey::new(key))
+    }
+}
+
+fn storage_error(message: &str) -> OneDriveError {
+    OneDriveError::TokenStorage(message.to_string())
+}
@@ -0,0 +1,78 @@
+//! OneDrive's QuickXorHash, the content hash every OneDrive drive reports for its files.
+//!
+//! See <https://learn.microsoft.com/en-us/onedrive/developer/code-snippets/quickxorhash>.
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+
+const WIDTH_IN_BITS: usize = 160;
+const SHIFT: usize = 11;
+const CELLS: usize = WIDTH_IN_BITS.div_ceil(64);
+
+/// Incremental QuickXorHash, fed in any number of chunks.
+pub struct QuickXorHash {
+    data: [u64; CELLS],
+    shift_so_far: usize,
+    length_so_far: u64,
+}
+
+impl QuickXorHash {
+    pub fn new() -> Self {
+        Self {
+            data: [0; CELLS],
+            shift_so_far: 0,
+            length_so_far: 0,
+        }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        let mut cell = self.shift_so_far / 64;
+        let mut offset = self.shift_so_far % 64;
+
+        for i in 0..bytes.len().min(WIDTH_IN_BITS) {
+            let is_last_cell = cell == CELLS - 1;
+            let bits_in_cell = if is_last_cell { WIDTH_IN_BITS % 64 } else { 64 };
+
+            // Every byte WIDTH_IN_BITS apart lands on the same bits
+            let lane = bytes.iter().skip(i).step_by(WIDTH_IN_BITS);
+            if offset <= bits_in_cell - 8 {
+                for &byte in lane {
+                    self.data[cell] ^= u64::from(byte) << offset;
+                }
+            } else {
+                // The byte straddles two cells
+                let xored = lane.fold(0u8, |acc, &byte| acc ^ byte);
+                let next_cell = if is_last_cell { 0 } else { cell + 1 };
+                self.data[cell] ^= u64::from(xored) << offset;
+                self.data[next_cell] ^= u64::from(xored) >> (bits_in_cell - offset);
+            }
+
+            offset += SHIFT;
+            while offset >= bits_in_cell {
+                cell = if is_last_cell { 0 } else { cell + 1 };
+                offset -= bits_in_cell;
+            }
+        }
+
+        self.shift_so_far =
+            (self.shift_so_far + SHIFT * (bytes.len() % WIDTH_IN_BITS)) % WIDTH_IN_BITS;
+        self.length_so_far += bytes.len() as u64;
+    }
+
+    /// Returns the base64-encoded hash, as OneDrive reports it.
+    pub fn finalize(self) -> String {
+        let mut hash = [0u8; WIDTH_IN_BITS / 8];
+        for (chunk, cell) in hash.chunks_mut(8).zip(self.data) {
+            chunk.copy_from_slice(&cell.to_le_bytes()[..chunk.len()]);
+        }
+
+        // The length is XORed into the last bytes
+        let length = self.length_so_far.to_le_bytes();
+        let start = hash.len() - length.len();
+        for (byte, length_byte) in hash[start..].iter_mut().zip(length) {
+            *byte ^= length_byte;
+        }
+
+        STANDARD.encode(hash)
+    }
+}
@@ -1,8 +1,72 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context as _, bail};
+use serde::Deserialize;
+
+/// Environment variable pointing at a bot config file, overriding `config.toml` in the manifest
+/// directory.
+const CONFIG_PATH_VAR: &str = "BOT_CONFIG_PATH";
+
 pub struct BotConfig {
     /// Token allowing bot to connect bot to Discord
     pub discord_token: String,
 }
 
+/// Bot config read from a TOML file. Unknown keys are ignored so the file can be shared with a
+/// bot's own config.
+#[derive(Debug, Default, Deserialize)]
+struct BotConfigFile {
+    discord_token: Option<String>,
+}
+
+impl BotConfig {
+    /// Load bot config from the environment, falling back to a TOML config file.
+    ///
+    /// The file is `BOT_CONFIG_PATH` if set, otherwise `config.toml` in `manifest_dir`, which is
+    /// optional. Environment variables take precedence over the file.
+    pub fn load(manifest_dir: &Path) -> anyhow::Result<Self> {
+        let (path, file) = match env::var_os(CONFIG_PATH_VAR) {
+            Some(path) => {
+                let path = PathBuf::from(path);
+                let file = read_config_file(&path)?.context(format!(
+                    "{CONFIG_PATH_VAR} is set but {} doesn't exist",
+                    path.display()
+                ))?;
+                (path, file)
+            }
+            None => {
+                let path = manifest_dir.join("config.toml");
+                let file = read_config_file(&path)?.unwrap_or_default();
+                (path, file)
+            }
+        };
+
+        let Some(discord_token) = env::var("DISCORD_TOKEN").ok().or(file.discord_token) else {
+            bail!(
+                "Expected DISCORD_TOKEN in environment or discord_token in {}",
+                path.display()
+            );
+        };
+
+        Ok(Self { discord_token })
+    }
+}
+
+/// Read a bot config file, returning `None` if it doesn't exist.
+fn read_config_file(path: &Path) -> anyhow::Result<Option<BotConfigFile>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).context(format!("Error reading {}", path.display())),
+    };
+
+    toml::from_str(&content)
+        .map(Some)
+        .context(format!("Error parsing {}", path.display()))
+}
+
 /// Load bot config using the calling crate's manifest directory.
 #[macro_export]
 macro_rules! load_bot_config {
@@ -15,11 +79,6 @@ macro_rules! load_bot_config {
         )
         .context("Can't find .env file")?;
 
-        Ok::<$crate::config::BotConfig, $crate::__private::anyhow::Error>(
-            $crate::config::BotConfig {
-                discord_token: std::env::var("DISCORD_TOKEN")
-                    .context("Expected DISCORD_TOKEN in environment")?,
-            },
-        )
+        $crate::config::BotConfig::load(std::path::Path::new(env!("CARGO_MANIFEST_DIR")))
     }};
 }
@@ -0,0 +1,187 @@
+use std::fmt;
+
+use chrono::Duration;
+
+/// Why a duration string couldn't be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    Empty,
+    MissingNumber(String),
+    MissingUnit(String),
+    UnknownUnit(String),
+    Zero,
+    TooLarge,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "duration is empty"),
+            Self::MissingNumber(part) => write!(f, "expected a number before `{part}`"),
+            Self::MissingUnit(amount) => {
+                write!(f, "`{amount}` is missing a unit (s, m, h, d or w)")
+            }
+            Self::UnknownUnit(unit) => write!(f, "unknown unit `{unit}`, expected s, m, h, d or w"),
+            Self::Zero => write!(f, "duration must be greater than zero"),
+            Self::TooLarge => write!(f, "duration is too large"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Parse a human duration such as `30m`, `7d` or `1h30m`.
+///
+/// Units are `s`, `m`, `h`, `d` and `w`, and whitespace between parts is ignored. Zero and
+/// negative durations are rejected.
+pub fn parse_duration(input: &str) -> Result<Duration, ParseError> {
+    let input: String = input.split_whitespace().collect();
+    if input.is_empty() {
+        return Err(ParseError::Empty);
+    }
+
+    let mut total = Duration::zero();
+    let mut rest = input.as_str();
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let (amount, after) = rest.split_at(digits);
+        let unit_len = after
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(after.len());
+        let (unit, after) = after.split_at(unit_len);
+        rest = after;
+
+        if amount.is_empty() {
+            return Err(ParseError::MissingNumber(unit.to_string()));
+        }
+        let seconds_per_unit = match unit {
+            "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            "w" => 7 * 24 * 60 * 60,
+            "" => return Err(ParseError::MissingUnit(amount.to_string())),
+            other => return Err(ParseError::UnknownUnit(other.to_string())),
+        };
+
+        let part = amount
+            .parse::<i64>()
+            .ok()
+            .and_then(|amount| amount.checked_mul(seconds_per_unit))
+            .and_then(Duration::try_seconds)
+            .ok_or(ParseError::TooLarge)?;
+        total = total.checked_add(&part).ok_or(ParseError::TooLarge)?;
+    }
+
+    if total.is_zero() {
+        return Err(ParseError::Zero);
+    }
+
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_each_unit() {
+        assert_eq!(parse_duration("45s"), Ok(Duration::seconds(45)));
+        assert_eq!(parse_duration("30m"), Ok(Duration::minutes(30)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::hours(2)));
+        assert_eq!(parse_duration("7d"), Ok(Duration::days(7)));
+        assert_eq!(parse_duration("1w"), Ok(Duration::weeks(1)));
+    }
+
+    #[test]
+    fn parses_compound_durations() {
+        assert_eq!(
+            parse_duration("1h30m"),
+            Ok(Duration::hours(1) + Duration::minutes(30))
+        );
+        assert_eq!(
+            parse_duration("1w2d3h4m5s"),
+            Ok(Duration::weeks(1)
+                + Duration::days(2)
+                + Duration::hours(3)
+                + Duration::minutes(4)
+                + Duration::seconds(5))
+        );
+    }
+
+    #[test]
+    fn ignores_whitespace() {
+        assert_eq!(
+            parse_duration(" 1h 30m "),
+            Ok(Duration::hours(1) + Duration::minutes(30))
+        );
+        assert_eq!(parse_duration("1 h"), Ok(Duration::hours(1)));
+    }
+
+    #[test]
+    fn repeated_units_add_up() {
+        assert_eq!(parse_duration("1m1m"), Ok(Duration::minutes(2)));
+    }
+
+    #[test]
+    fn rejects_empty_input() {
+        assert_eq!(parse_duration(""), Err(ParseError::Empty));
+        assert_eq!(parse_duration("   "), Err(ParseError::Empty));
+    }
+
+    #[test]
+    fn rejects_zero() {
+        assert_eq!(parse_duration("0s"), Err(ParseError::Zero));
+        assert_eq!(parse_duration("0h0m"), Err(ParseError::Zero));
+    }
+
+    #[test]
+    fn rejects_a_bare_number() {
+        assert_eq!(
+            parse_duration("30"),
+            Err(ParseError::MissingUnit("30".to_owned()))
+        );
+        assert_eq!(
+            parse_duration("1h30"),
+            Err(ParseError::MissingUnit("30".to_owned()))
+        );
+    }
+
+    #[test]
+    fn rejects_negative_durations() {
+        assert_eq!(
+            parse_duration("-5m"),
+            Err(ParseError::MissingNumber("-".to_owned()))
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_units() {
+        assert_eq!(
+            parse_duration("5y"),
+            Err(ParseError::UnknownUnit("y".to_owned()))
+        );
+        assert_eq!(
+            parse_duration("5min"),
+            Err(ParseError::UnknownUnit("min".to_owned()))
+        );
+    }
+
+    #[test]
+    fn rejects_overflow() {
+        assert_eq!(
+            parse_duration("99999999999999999999s"),
+            Err(ParseError::TooLarge)
+        );
+        assert_eq!(
+            parse_duration(&format!("{}w", i64::MAX)),
+            Err(ParseError::TooLarge)
+        );
+        assert_eq!(
+            parse_duration(&format!("{}s{}s", i64::MAX / 1000, i64::MAX / 1000)),
+            Err(ParseError::TooLarge)
+        );
+    }
+}
@@ -1,4 +1,5 @@
 pub mod config;
+pub mod duration;
 pub mod tracing;
 
 /// Re-exports used by macros. Not public API.
@@ -7,6 +8,4 @@ pub mod __private {
     pub use anyhow;
     #[cfg(debug_assertions)]
     pub use dotenvy;
-    pub use tracing_journald;
-    pub use tracing_subscriber;
 }
@@ -1,36 +1,244 @@
+use std::sync::OnceLock;
+use std::{env, fmt};
+
+use anyhow::Context as _;
+use serde_json::{Map, Value};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::{
+    EnvFilter, Layer,
+    fmt::format::{FmtSpan, JsonFields, Writer},
+    fmt::time::{FormatTime, SystemTime},
+    fmt::{FmtContext, FormatEvent, FormatFields, FormattedFields, MakeWriter},
+    layer::SubscriberExt as _,
+    registry::LookupSpan,
+    util::SubscriberInitExt as _,
+};
+
+/// Keeps the log file writer flushing for the rest of the process.
+static FILE_WRITER_GUARD: OnceLock<WorkerGuard> = OnceLock::new();
+
+/// Initialize tracing for a package, logging `<package>=info` unless `RUST_LOG` says otherwise.
+/// Directives in `LOG_OVERRIDES` (e.g. `cleanup_bot::backup=debug,serenity=warn`) are applied on
+/// top.
+///
+/// Logs go to journald when it's available. Otherwise they're written to stderr, as JSON if
+/// `LOG_FORMAT=json`. If `LOG_DIR` is set, logs are also written to daily-rotated files there.
+/// JSON logs are one object per event, with the fields of the spans it's in alongside its own.
+pub fn init(package_name: &str) -> anyhow::Result<()> {
+    let default_directive = format!("{}=info", package_name.replace("-", "_"));
+    let filter = EnvFilter::builder()
+        .with_default_directive(default_directive.parse()?)
+        .from_env_lossy();
+    let filter = apply_overrides(filter)?;
+    let json = env::var("LOG_FORMAT").is_ok_and(|format| format.eq_ignore_ascii_case("json"));
+
+    let journald_layer = tracing_journald::layer().ok();
+    let console_layer = journald_layer
+        .is_none()
+        .then(|| fmt_layer(std::io::stderr, json, true));
+
+    let file_layer = match env::var_os("LOG_DIR") {
+        Some(log_dir) => {
+            let appender = RollingFileAppender::builder()
+                .rotation(Rotation::DAILY)
+                .filename_prefix(package_name)
+                .filename_suffix("log")
+                .build(&log_dir)
+                .context(format!(
+                    "Error creating log file in {}",
+                    log_dir.to_string_lossy()
+                ))?;
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            let _ = FILE_WRITER_GUARD.set(guard);
+            Some(fmt_layer(writer, json, false))
+        }
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(journald_layer)
+        .with(console_layer)
+        .with(file_layer)
+        .init();
+
+    Ok(())
+}
+
+/// Add the comma-separated directives in `LOG_OVERRIDES` to the filter, in order.
+fn apply_overrides(mut filter: EnvFilter) -> anyhow::Result<EnvFilter> {
+    let Ok(overrides) = env::var("LOG_OVERRIDES") else {
+        return Ok(filter);
+    };
+
+    for directive in overrides
+        .split(',')
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+    {
+        filter = filter.add_directive(
+            directive
+                .parse()
+                .context(format!("Invalid directive `{directive}` in LOG_OVERRIDES"))?,
+        );
+    }
+
+    Ok(filter)
+}
+
+fn fmt_layer<S, W>(writer: W, json: bool, ansi: bool) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi);
+
+    if json {
+        layer
+            .fmt_fields(JsonFields::new())
+            .event_format(FlatJson)
+            .boxed()
+    } else {
+        layer
+            .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
+            .boxed()
+    }
+}
+
+/// Formats each event as a single JSON object, with the fields of the spans it's in flattened in
+/// beside its own so log shippers can index them. Inner spans' fields win over outer ones', and
+/// the event's over both.
+struct FlatJson;
+
+impl<S, N> FormatEvent<S, N> for FlatJson
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut timestamp = String::new();
+        SystemTime.format_time(&mut Writer::new(&mut timestamp))?;
+        let metadata = event.metadata();
+
+        let mut object = Map::new();
+        object.insert("timestamp".to_owned(), timestamp.into());
+        object.insert("level".to_owned(), metadata.level().as_str().into());
+        object.insert("target".to_owned(), metadata.target().into());
+
+        for span in ctx
+            .event_scope()
+            .into_iter()
+            .flat_map(|scope| scope.from_root())
+        {
+            let extensions = span.extensions();
+            // Recorded by the JsonFields formatter, so already a JSON object
+            if let Some(fields) = extensions.get::<FormattedFields<N>>()
+                && let Ok(Value::Object(fields)) = serde_json::from_str(fields)
+            {
+                object.extend(fields);
+            }
+        }
+        event.record(&mut JsonVisitor(&mut object));
+
+        writeln!(writer, "{}", Value::Object(object))
+    }
+}
+
+/// Records an event's fields into a JSON object.
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_owned(), format!("{value:?}").into());
+    }
+}
+
 /// Initialize tracing using the calling crate's package name.
 #[macro_export]
 macro_rules! init_tracing {
-    () => {{
-        use $crate::__private::tracing_subscriber::{
-            EnvFilter, fmt::format::FmtSpan, layer::SubscriberExt as _,
-            util::SubscriberInitExt as _,
-        };
-
-        let default_directive = format!("{}=info", env!("CARGO_PKG_NAME").replace("-", "_"),);
-
-        match $crate::__private::tracing_journald::layer() {
-            Ok(journald_layer) => $crate::__private::tracing_subscriber::registry()
-                .with(
-                    EnvFilter::builder()
-                        .with_default_directive(default_directive.parse()?)
-                        .from_env_lossy(),
-                )
-                .with(journald_layer)
-                .init(),
-            Err(_) => $crate::__private::tracing_subscriber::registry()
-                .with(
-                    EnvFilter::builder()
-                        .with_default_directive(default_directive.parse()?)
-                        .from_env_lossy(),
-                )
-                .with(
-                    $crate::__private::tracing_subscriber::fmt::layer()
-                        .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE),
-                )
-                .init(),
-        };
-
-        Ok::<(), $crate::__private::anyhow::Error>(())
-    }};
+    () => {{ $crate::tracing::init(env!("CARGO_PKG_NAME")) }};
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    use tracing::{info, info_span};
+    use tracing_subscriber::registry;
+
+    use super::*;
+
+    /// Collects everything written to it.
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn json_logs_flatten_span_fields_into_the_event() {
+        let capture = Capture::default();
+        let writer = capture.clone();
+        let subscriber = registry().with(fmt_layer(move || writer.clone(), true, false));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let outer = info_span!("cleanup", channel = 5, attempt = 1);
+            let _outer = outer.enter();
+            let inner = info_span!("thread", thread = "general", attempt = 2);
+            let _inner = inner.enter();
+            info!(deleted = 3, "Cleanup completed");
+        });
+
+        let output = String::from_utf8(capture.0.lock().unwrap().clone()).unwrap();
+        let event: Value = serde_json::from_str(output.trim()).unwrap();
+        assert_eq!(event["message"], "Cleanup completed");
+        assert_eq!(event["level"], "INFO");
+        assert_eq!(event["deleted"], 3);
+        assert_eq!(event["channel"], 5);
+        assert_eq!(event["thread"], "general");
+        // The innermost span's value wins
+        assert_eq!(event["attempt"], 2);
+        assert!(event.get("span").is_none());
+        assert!(event["timestamp"].is_string());
+    }
 }
@@ -0,0 +1,75 @@
+//! Slash commands offered by the bot.
+
+use std::num::NonZeroU64;
+
+use serenity::all::{
+    ChannelId, CommandOptionType, CreateCommand, CreateCommandOption, GuildId, MessageId,
+};
+
+/// Name of the command that summarizes a linked message.
+pub const SUMMARIZE: &str = "summarize";
+
+/// Name of the `/summarize` option holding the message link.
+pub const MESSAGE_LINK: &str = "message_link";
+
+/// Hosts that serve Discord message links.
+const DISCORD_HOSTS: [&str; 4] = [
+    "discord.com",
+    "ptb.discord.com",
+    "canary.discord.com",
+    "discordapp.com",
+];
+
+/// Builds the `/summarize message_link:<url>` command.
+pub fn summarize() -> CreateCommand {
+    CreateCommand::new(SUMMARIZE)
+        .description("Privately summarize a message")
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::String,
+                MESSAGE_LINK,
+                "Link to the message to summarize",
+            )
+            .required(true),
+        )
+}
+
+/// The message a Discord message link points at.
+#[derive(Debug, Clone, Copy)]
+pub struct MessageLink {
+    /// `None` for messages in DMs.
+    pub guild_id: Option<GuildId>,
+    pub channel_id: ChannelId,
+    pub message_id: MessageId,
+}
+
+/// Parses a link of the form
+/// `https://discord.com/channels/<guild id or @me>/<channel id>/<message id>`.
+pub fn parse_message_link(link: &str) -> Option<MessageLink> {
+    let rest = link.trim().strip_prefix("https://")?;
+    let (host, path) = rest.split_once('/')?;
+    if !DISCORD_HOSTS.contains(&host) {
+        return None;
+    }
+
+    let mut segments = path.strip_prefix("channels/")?.split('/');
+    let guild = segments.next()?;
+    let channel = segments.next()?;
+    let message = segments.next()?;
+    if segments.next().is_some() {
+        return None;
+    }
+
+    // Ids are parsed as non-zero since serenity's id constructors panic on 0
+    let id = |segment: &str| segment.parse::<NonZeroU64>().ok().map(NonZeroU64::get);
+    let guild_id = match guild {
+        "@me" => None,
+        guild => Some(GuildId::new(id(guild)?)),
+    };
+
+    Some(MessageLink {
+        guild_id,
+        channel_id: ChannelId::new(id(channel)?),
+        message_id: MessageId::new(id(message)?),
+    })
+}
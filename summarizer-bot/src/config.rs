@@ -1,32 +1,136 @@
+use std::collections::HashSet;
 use std::env;
-use std::fs;
 use std::path::PathBuf;
 use std::time::Duration;
 
 use anyhow::{Context, Result, anyhow};
+use ollama_rs::generation::parameters::{KeepAlive, TimeUnit};
+use serenity::all::ChannelId;
 use shared::config::BotConfig;
 
+use crate::prompt;
+
+/// Service identifier reported with metrics when `BOT_NAME` is unset.
+const DEFAULT_METRICS_SOURCE: &str = "summarizer-bot";
+
 /// Default interval between automatic heartbeats when `METRICS_HEARTBEAT_INTERVAL`
 /// is unset.
 const DEFAULT_HEARTBEAT_INTERVAL_SECS: u64 = 30;
 
+/// Default number of attempts at generating a summary when `LLM_MAX_RETRIES`
+/// is unset.
+const DEFAULT_LLM_MAX_RETRIES: u32 = 3;
+
+/// Default limit on how long a summary may take to generate when
+/// `LLM_TIMEOUT_SECONDS` is unset.
+const DEFAULT_LLM_TIMEOUT_SECS: u64 = 600;
+
+/// Default number of generations run at once when `LLM_MAX_CONCURRENCY` is
+/// unset.
+const DEFAULT_LLM_MAX_CONCURRENCY: usize = 2;
+
+/// Default limit on summary length when `SUMMARY_MAX_CHARS` is unset. Leaves
+/// room for the heading within Discord's 2000 character message limit.
+const DEFAULT_SUMMARY_MAX_CHARS: usize = 1800;
+
+/// Default overlap between the parts of a chunked message when `CHUNK_OVERLAP`
+/// is unset.
+const DEFAULT_CHUNK_OVERLAP: usize = 200;
+
+/// Default emoji that requests a summary when `SUMMARIZE_EMOJI` is unset.
+const DEFAULT_SUMMARIZE_EMOJI: &str = "📝";
+
+/// Default minimum time between streamed summary edits when `EDIT_THROTTLE_MS`
+/// is unset.
+const DEFAULT_EDIT_THROTTLE_MS: u64 = 750;
+
+/// Default size limit on images sent to the vision model when
+/// `LLM_IMAGE_MAX_BYTES` is unset.
+const DEFAULT_LLM_IMAGE_MAX_BYTES: u32 = 5 * 1024 * 1024;
+
 pub struct Config {
     pub bot: BotConfig,
     pub llm_model: String,
+    /// Model used instead of `llm_model` for messages with image attachments.
+    /// `None` when unset, in which case images are ignored.
+    pub llm_vision_model: Option<String>,
+    /// Images larger than this are not sent to the vision model.
+    pub llm_image_max_bytes: u32,
     pub llm_host: String,
     pub llm_port: u16,
+    /// How many times to attempt a generation when the LLM backend can't be
+    /// reached. Always at least 1.
+    pub llm_max_retries: u32,
+    /// How many generations may run at once; further summaries queue. Always
+    /// at least 1.
+    pub llm_max_concurrency: usize,
+    /// How long a whole generation may take, streaming included, before it's
+    /// abandoned.
+    pub llm_timeout: Duration,
+    /// How long Ollama keeps the model loaded after each summary. Defaults to
+    /// five minutes, matching Ollama's own default.
+    pub llm_keep_alive: KeepAlive,
     pub message_length_min: usize,
     pub message_length_max: usize,
-    /// System prompt for the summarizer, loaded from `system_prompt.txt` in the
-    /// app's data directory at startup. Restart the service to pick up edits.
+    /// Messages longer than `message_length_max` are split into parts of at
+    /// most this many characters, summarized separately, then combined.
+    /// Defaults to `message_length_max`.
+    pub chunk_window: usize,
+    /// How many characters each part overlaps the previous one by.
+    pub chunk_overlap: usize,
+    /// Channels summaries are limited to. `None` permits every channel.
+    pub summarize_channels: Option<HashSet<ChannelId>>,
+    /// Channels that are never summarized, DMs included. Takes precedence over
+    /// `summarize_channels`.
+    pub summarize_channels_deny: HashSet<ChannelId>,
+    /// Minimum time between edits of the placeholder message while a summary
+    /// is streaming in. Keeps the bot well clear of Discord's edit rate limit.
+    pub edit_throttle: Duration,
+    /// What triggers a summary. Defaults to [`SummarizeMode::Auto`].
+    pub summarize_mode: SummarizeMode,
+    /// Reacting to a message with this emoji requests a summary of it. Only
+    /// used in [`SummarizeMode::Reaction`].
+    pub summarize_emoji: String,
+    /// The model is asked to keep summaries under this many characters, and
+    /// longer ones are cut short.
+    pub summary_max_chars: usize,
+    /// Which language summaries are written in. `None` leaves it to the model.
+    pub summary_language: Option<SummaryLanguage>,
+    /// Where the system prompt is read from. The file is watched, so edits
+    /// take effect without a restart.
+    pub system_prompt_path: PathBuf,
+    /// System prompt for the summarizer as of startup.
     pub system_prompt: String,
     /// Metrics reporting config. `None` when the `METRICS_*` env vars are unset,
     /// in which case the bot runs without reporting metrics.
     pub metrics: Option<MetricsConfig>,
 }
 
+/// What triggers a summary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SummarizeMode {
+    /// Messages within the configured length window are summarized as they're
+    /// posted.
+    Auto,
+    /// Messages are only summarized when someone reacts with the configured
+    /// emoji.
+    Reaction,
+}
+
+/// Which language summaries are written in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SummaryLanguage {
+    /// The language the summarized message is written in.
+    Match,
+    /// A fixed language, by name or code (e.g. `en`).
+    Fixed(String),
+}
+
 /// Config for reporting metrics to a service-panel instance.
 pub struct MetricsConfig {
+    /// Service identifier reported with every metric and heartbeat, so
+    /// several instances can be told apart. Read from `BOT_NAME`.
+    pub source: String,
     pub ingest_endpoint: String,
     pub heartbeat_endpoint: String,
     pub heartbeat_interval: Duration,
@@ -34,23 +138,51 @@ pub struct MetricsConfig {
 
 impl Config {
     pub fn from_env() -> Result<Self> {
+        let system_prompt_path = system_prompt_path();
+        let message_length_max = env::var("MESSAGE_LENGTH_MAX")
+            .context("Expected MESSAGE_LENGTH_MAX in environment")?
+            .parse()
+            .context("MESSAGE_LENGTH_MAX must be a valid number")?;
+        let (chunk_window, chunk_overlap) = load_chunking(message_length_max)?;
         let config = Self {
             bot: shared::load_bot_config!()?,
             llm_model: env::var("LLM_MODEL").context("Expected LLM_MODEL in environment")?,
+            llm_vision_model: env::var("LLM_VISION_MODEL")
+                .ok()
+                .filter(|model| !model.trim().is_empty()),
+            llm_image_max_bytes: match env::var("LLM_IMAGE_MAX_BYTES") {
+                Ok(bytes) => bytes
+                    .parse()
+                    .context("LLM_IMAGE_MAX_BYTES must be a valid number")?,
+                Err(_) => DEFAULT_LLM_IMAGE_MAX_BYTES,
+            },
             llm_host: env::var("LLM_HOST").context("Expected LLM_HOST in environment")?,
             llm_port: env::var("LLM_PORT")
                 .context("Expected LLM_PORT in environment")?
                 .parse()
                 .context("LLM_PORT must be a valid port number")?,
+            llm_max_retries: load_llm_max_retries()?,
+            llm_max_concurrency: load_llm_max_concurrency()?,
+            llm_timeout: load_llm_timeout()?,
+            llm_keep_alive: load_llm_keep_alive()?,
             message_length_min: env::var("MESSAGE_LENGTH_MIN")
                 .context("Expected MESSAGE_LENGTH_MIN in environment")?
                 .parse()
                 .context("MESSAGE_LENGTH_MIN must be a valid number")?,
-            message_length_max: env::var("MESSAGE_LENGTH_MAX")
-                .context("Expected MESSAGE_LENGTH_MAX in environment")?
-                .parse()
-                .context("MESSAGE_LENGTH_MAX must be a valid number")?,
-            system_prompt: load_system_prompt()?,
+            message_length_max,
+            chunk_window,
+            chunk_overlap,
+            summarize_channels: load_channel_ids("SUMMARIZE_CHANNELS")?,
+            summarize_channels_deny: load_channel_ids("SUMMARIZE_CHANNELS_DENY")?
+                .unwrap_or_default(),
+            edit_throttle: load_edit_throttle()?,
+            summarize_mode: load_summarize_mode()?,
+            summary_max_chars: load_summary_max_chars()?,
+            summary_language: load_summary_language(),
+            summarize_emoji: env::var("SUMMARIZE_EMOJI")
+                .unwrap_or_else(|_| DEFAULT_SUMMARIZE_EMOJI.to_string()),
+            system_prompt: prompt::read_system_prompt(&system_prompt_path)?,
+            system_prompt_path,
             metrics: load_metrics_config()?,
         };
 
@@ -62,6 +194,185 @@ impl Config {
     }
 }
 
+/// Reads `CHUNK_WINDOW` and `CHUNK_OVERLAP`. The window defaults to
+/// `message_length_max`, and the default overlap is capped at a quarter of the
+/// window so small windows still work without setting either.
+fn load_chunking(message_length_max: usize) -> Result<(usize, usize)> {
+    let window = match env::var("CHUNK_WINDOW") {
+        Ok(window) => window
+            .parse()
+            .context("CHUNK_WINDOW must be a valid number")?,
+        Err(_) => message_length_max,
+    };
+    if window == 0 {
+        return Err(anyhow!(
+            "CHUNK_WINDOW (MESSAGE_LENGTH_MAX when unset) must be greater than 0"
+        ));
+    }
+
+    let overlap = match env::var("CHUNK_OVERLAP") {
+        Ok(overlap) => {
+            let overlap = overlap
+                .parse()
+                .context("CHUNK_OVERLAP must be a valid number")?;
+            // Each part must advance past the previous one
+            if overlap >= window {
+                return Err(anyhow!("CHUNK_OVERLAP must be < CHUNK_WINDOW"));
+            }
+            overlap
+        }
+        Err(_) => DEFAULT_CHUNK_OVERLAP.min(window / 4),
+    };
+
+    Ok((window, overlap))
+}
+
+/// Reads `LLM_MAX_RETRIES`, falling back to the default when unset.
+fn load_llm_max_retries() -> Result<u32> {
+    let retries = match env::var("LLM_MAX_RETRIES") {
+        Ok(retries) => retries
+            .parse()
+            .context("LLM_MAX_RETRIES must be a valid number")?,
+        Err(_) => DEFAULT_LLM_MAX_RETRIES,
+    };
+    // Zero attempts would never summarize anything.
+    if retries == 0 {
+        return Err(anyhow!("LLM_MAX_RETRIES must be greater than zero"));
+    }
+    Ok(retries)
+}
+
+/// Reads `LLM_MAX_CONCURRENCY`, falling back to the default when unset.
+fn load_llm_max_concurrency() -> Result<usize> {
+    let concurrency = match env::var("LLM_MAX_CONCURRENCY") {
+        Ok(concurrency) => concurrency
+            .parse()
+            .context("LLM_MAX_CONCURRENCY must be a valid number")?,
+        Err(_) => DEFAULT_LLM_MAX_CONCURRENCY,
+    };
+    // No permits would queue every summary forever.
+    if concurrency == 0 {
+        return Err(anyhow!("LLM_MAX_CONCURRENCY must be greater than zero"));
+    }
+    Ok(concurrency)
+}
+
+/// Reads `LLM_TIMEOUT_SECONDS`, falling back to the default when unset.
+fn load_llm_timeout() -> Result<Duration> {
+    let secs = match env::var("LLM_TIMEOUT_SECONDS") {
+        Ok(secs) => secs
+            .parse()
+            .context("LLM_TIMEOUT_SECONDS must be a number of seconds")?,
+        Err(_) => DEFAULT_LLM_TIMEOUT_SECS,
+    };
+    // A zero timeout would fail every summary.
+    if secs == 0 {
+        return Err(anyhow!("LLM_TIMEOUT_SECONDS must be greater than zero"));
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+/// Reads `LLM_KEEP_ALIVE`, using Ollama's syntax: `-1` keeps the model loaded
+/// indefinitely, `0` unloads it after each summary, and a number with an `s`,
+/// `m`, or `h` suffix (e.g. `10m`) keeps it loaded that long.
+fn load_llm_keep_alive() -> Result<KeepAlive> {
+    let Ok(keep_alive) = env::var("LLM_KEEP_ALIVE") else {
+        return Ok(KeepAlive::Until {
+            time: 5,
+            unit: TimeUnit::Minutes,
+        });
+    };
+
+    let invalid =
+        || anyhow!("LLM_KEEP_ALIVE must be -1, 0, or a duration like 10m, got \"{keep_alive}\"");
+    match keep_alive.trim() {
+        "-1" => Ok(KeepAlive::Indefinitely),
+        "0" => Ok(KeepAlive::UnloadOnCompletion),
+        duration => {
+            let (time, unit) = [
+                ('s', TimeUnit::Seconds),
+                ('m', TimeUnit::Minutes),
+                ('h', TimeUnit::Hours),
+            ]
+            .into_iter()
+            .find_map(|(suffix, unit)| Some((duration.strip_suffix(suffix)?, unit)))
+            .ok_or_else(invalid)?;
+            let time = time.parse().map_err(|_| invalid())?;
+            Ok(KeepAlive::Until { time, unit })
+        }
+    }
+}
+
+/// Reads a comma-separated list of channel ids from `key`. Returns `None` when
+/// the variable is unset or blank.
+fn load_channel_ids(key: &str) -> Result<Option<HashSet<ChannelId>>> {
+    let Some(value) = env::var(key).ok().filter(|value| !value.trim().is_empty()) else {
+        return Ok(None);
+    };
+
+    value
+        .split(',')
+        .map(|id| {
+            let id = id.trim();
+            id.parse::<u64>()
+                .ok()
+                .filter(|id| *id != 0)
+                .map(ChannelId::new)
+                .with_context(|| format!("{key} contains an invalid channel id: \"{id}\""))
+        })
+        .collect::<Result<_>>()
+        .map(Some)
+}
+
+/// Reads `EDIT_THROTTLE_MS`, falling back to the default when unset.
+fn load_edit_throttle() -> Result<Duration> {
+    let millis = match env::var("EDIT_THROTTLE_MS") {
+        Ok(millis) => millis
+            .parse()
+            .context("EDIT_THROTTLE_MS must be a number of milliseconds")?,
+        Err(_) => DEFAULT_EDIT_THROTTLE_MS,
+    };
+    Ok(Duration::from_millis(millis))
+}
+
+/// Reads `SUMMARIZE_MODE`, defaulting to automatic summaries so existing
+/// deployments keep their behavior.
+fn load_summarize_mode() -> Result<SummarizeMode> {
+    match env::var("SUMMARIZE_MODE").as_deref() {
+        Ok("auto") | Err(_) => Ok(SummarizeMode::Auto),
+        Ok("reaction") => Ok(SummarizeMode::Reaction),
+        Ok(other) => Err(anyhow!(
+            "SUMMARIZE_MODE must be \"auto\" or \"reaction\", got \"{other}\""
+        )),
+    }
+}
+
+/// Reads `SUMMARY_MAX_CHARS`, falling back to the default when unset.
+fn load_summary_max_chars() -> Result<usize> {
+    let max_chars = match env::var("SUMMARY_MAX_CHARS") {
+        Ok(max_chars) => max_chars
+            .parse()
+            .context("SUMMARY_MAX_CHARS must be a valid number")?,
+        Err(_) => DEFAULT_SUMMARY_MAX_CHARS,
+    };
+    // Every summary would be cut down to nothing.
+    if max_chars == 0 {
+        return Err(anyhow!("SUMMARY_MAX_CHARS must be greater than zero"));
+    }
+    Ok(max_chars)
+}
+
+/// Reads `SUMMARY_LANGUAGE`: `match` to reply in each message's language, or
+/// a language to always reply in. Unset or blank leaves it to the model.
+fn load_summary_language() -> Option<SummaryLanguage> {
+    let language = env::var("SUMMARY_LANGUAGE").ok()?;
+    match language.trim() {
+        "" => None,
+        "match" => Some(SummaryLanguage::Match),
+        language => Some(SummaryLanguage::Fixed(language.to_owned())),
+    }
+}
+
 /// Reads the optional metrics config.
 ///
 /// Metrics are enabled only when both `METRICS_INGEST_ENDPOINT` and
@@ -95,6 +406,7 @@ fn load_metrics_config() -> Result<Option<MetricsConfig>> {
             };
 
             Ok(Some(MetricsConfig {
+                source: read("BOT_NAME").unwrap_or_else(|| DEFAULT_METRICS_SOURCE.to_owned()),
                 ingest_endpoint,
                 heartbeat_endpoint,
                 heartbeat_interval,
@@ -106,25 +418,65 @@ fn load_metrics_config() -> Result<Option<MetricsConfig>> {
     }
 }
 
-/// Reads the system prompt from `system_prompt.txt`.
+/// Resolves the system prompt file.
 ///
-/// In release builds the file is resolved relative to the working directory
-/// (the systemd `WorkingDirectory`, i.e. the app's data directory), so the
-/// prompt can be edited and picked up with a service restart — no rebuild
-/// required. In debug builds it is resolved relative to the crate's manifest
-/// directory for convenient local development, mirroring how `.env` is loaded.
-fn load_system_prompt() -> Result<String> {
-    let path = system_prompt_path();
-    fs::read_to_string(&path)
-        .with_context(|| format!("Failed to read system prompt from {}", path.display()))
+/// `SYSTEM_PROMPT_PATH` takes precedence when set. Otherwise, in release builds
+/// the file is `system_prompt.txt` relative to the working directory (the
+/// systemd `WorkingDirectory`, i.e. the app's data directory), so the prompt can
+/// be edited in place — no rebuild required. In debug builds it is resolved
+/// relative to the crate's manifest directory for convenient local development,
+/// mirroring how `.env` is loaded.
+fn system_prompt_path() -> PathBuf {
+    match env::var("SYSTEM_PROMPT_PATH") {
+        Ok(path) if !path.is_empty() => PathBuf::from(path),
+        _ => default_system_prompt_path(),
+    }
 }
 
 #[cfg(debug_assertions)]
-fn system_prompt_path() -> PathBuf {
+fn default_system_prompt_path() -> PathBuf {
     std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("system_prompt.txt")
 }
 
 #[cfg(not(debug_assertions))]
-fn system_prompt_path() -> PathBuf {
+fn default_system_prompt_path() -> PathBuf {
     PathBuf::from("./system_prompt.txt")
 }
+
+/// A config with every setting at its default, for tests that need a
+/// [`Handler`](crate::handler::Handler) or
+/// [`SummaryGenerator`](crate::llm::SummaryGenerator) without an environment.
+#[cfg(test)]
+pub fn test_config() -> Config {
+    Config {
+        bot: BotConfig {
+            discord_token: String::new(),
+        },
+        llm_model: "llm".to_owned(),
+        llm_vision_model: None,
+        llm_image_max_bytes: DEFAULT_LLM_IMAGE_MAX_BYTES,
+        llm_host: "http://localhost".to_owned(),
+        llm_port: 11434,
+        llm_max_retries: DEFAULT_LLM_MAX_RETRIES,
+        llm_max_concurrency: DEFAULT_LLM_MAX_CONCURRENCY,
+        llm_timeout: Duration::from_secs(DEFAULT_LLM_TIMEOUT_SECS),
+        llm_keep_alive: KeepAlive::Until {
+            time: 5,
+            unit: TimeUnit::Minutes,
+        },
+        message_length_min: 0,
+        message_length_max: 1000,
+        chunk_window: 1000,
+        chunk_overlap: DEFAULT_CHUNK_OVERLAP,
+        summarize_channels: None,
+        summarize_channels_deny: HashSet::new(),
+        edit_throttle: Duration::from_millis(DEFAULT_EDIT_THROTTLE_MS),
+        summarize_mode: SummarizeMode::Auto,
+        summarize_emoji: DEFAULT_SUMMARIZE_EMOJI.to_owned(),
+        summary_max_chars: DEFAULT_SUMMARY_MAX_CHARS,
+        summary_language: None,
+        system_prompt_path: PathBuf::new(),
+        system_prompt: String::new(),
+        metrics: None,
+    }
+}
@@ -1,24 +1,77 @@
-use std::time::Instant;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, PoisonError};
+use std::time::{Duration, Instant};
 
+use base64::prelude::{BASE64_STANDARD, Engine as _};
+use lru::LruCache;
 use metrics_client::MetricsClient;
+use ollama_rs::generation::images::Image;
 use serenity::{
-    all::{CreateEmbed, CreateMessage, EditMessage, EventHandler, Mentionable, Message, Ready},
+    all::{
+        Attachment, Cache, Channel, ChannelId, Command, CommandInteraction, CreateEmbed,
+        CreateMessage, CreateThread, EditInteractionResponse, EditMessage, EventHandler,
+        GuildChannel, GuildId, Http, Interaction, Mention, Mentionable, Message, MessageId,
+        MessageUpdateEvent, Permissions, Reaction, Ready,
+    },
     async_trait,
 };
-use tracing::{error, info};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio::time::interval;
+use tracing::{error, info, warn};
 
 use crate::{
-    config::Config,
-    llm::{SummaryError, SummaryGenerator},
+    commands,
+    config::{Config, SummarizeMode},
+    llm::{Retry, SummaryError, SummaryGenerator, SummaryRequest, SummaryStream, chunk_content},
     metrics::{ApiOp, Event, Outcome, SkipReason, Source, label, value},
 };
 
+/// Discord's limit on message length. Streamed previews are truncated to fit.
+const DISCORD_MESSAGE_LIMIT: usize = 2000;
+
+/// How many posted summaries are remembered so edits to their messages can
+/// update them in place. The least recently used are forgotten first.
+const SUMMARY_CACHE_SIZE: NonZeroUsize = NonZeroUsize::new(1000).unwrap();
+
+/// How often the typing indicator is refreshed while a summary generates.
+/// Discord shows it for about 10 seconds per request.
+const TYPING_INTERVAL: Duration = Duration::from_secs(8);
+
 pub struct Handler {
     summary_generator: SummaryGenerator,
     // Messages at least this long are summarized
     message_length_min: usize,
-    // Messages longer than this are not summarized
+    // Messages longer than this are summarized in parts
     message_length_max: usize,
+    // Size of each part of a message summarized in parts
+    chunk_window: usize,
+    // How much each part overlaps the previous one
+    chunk_overlap: usize,
+    // When set, only these channels are summarized
+    summarize_channels: Option<HashSet<ChannelId>>,
+    // These channels are never summarized, DMs included
+    summarize_channels_deny: HashSet<ChannelId>,
+    // Attempts at starting a generation before giving up
+    llm_max_retries: u32,
+    // Minimum time between edits while a summary streams in
+    edit_throttle: Duration,
+    // Whether messages are summarized automatically or on request
+    summarize_mode: SummarizeMode,
+    // Reacting with this emoji requests a summary in reaction mode
+    summarize_emoji: String,
+    // Summaries longer than this are cut short
+    summary_max_chars: usize,
+    // Images larger than this aren't sent to the vision model
+    llm_image_max_bytes: u32,
+    // Messages with a summary currently being generated, so a message that
+    // triggers twice is only summarized once
+    in_flight: std::sync::Mutex<HashSet<MessageId>>,
+    // Where the summary of each recently summarized message was posted, so an
+    // edit to the message updates its summary instead of posting another
+    summaries: Mutex<LruCache<MessageId, (ChannelId, MessageId)>>,
     // Reports metrics to a service-panel instance. `None` when metrics are
     // disabled, in which case every emit is a no-op.
     metrics: Option<MetricsClient<Event>>,
@@ -32,21 +85,247 @@ impl EventHandler for Handler {
             return;
         }
 
-        let is_dm = msg.guild_id.is_none();
-        let source = if is_dm { Source::Dm } else { Source::Guild };
+        // In reaction mode summaries are only made on request
+        if self.summarize_mode == SummarizeMode::Reaction {
+            return;
+        }
+
+        if !self.should_summarize(&msg) {
+            self.record_skip(SkipReason::ChannelNotPermitted);
+            return;
+        }
+
+        // DMs are always summarized; guild messages must be long enough to be
+        // worth it.
+        if msg.guild_id.is_some() && msg.content.len() < self.message_length_min {
+            self.record_skip(SkipReason::TooShort);
+            return;
+        }
+
+        let Some(_in_flight) = self.begin_summary(msg.id) else {
+            self.record_skip(SkipReason::InFlight);
+            return;
+        };
+        self.summarize(&ctx, &msg, msg.channel_id, None).await;
+    }
+
+    async fn message_update(
+        &self,
+        ctx: serenity::client::Context,
+        _old_if_available: Option<Message>,
+        _new: Option<Message>,
+        event: MessageUpdateEvent,
+    ) {
+        // Updates also fire when Discord resolves link embeds; only edits to
+        // the content can change the summary
+        if event.content.is_none() {
+            return;
+        }
 
-        // DMs are always summarized; guild messages must fall within the
-        // configured length window.
-        if !is_dm {
-            if msg.content.len() < self.message_length_min {
-                self.record_skip(SkipReason::TooShort);
+        let previous = self.summaries.lock().await.get(&event.id).copied();
+
+        // In reaction mode an edit only refreshes a summary someone asked for
+        if previous.is_none() && self.summarize_mode == SummarizeMode::Reaction {
+            return;
+        }
+
+        let msg = match event.channel_id.message(&ctx.http, event.id).await {
+            Ok(msg) => msg,
+            Err(why) => {
+                error!("Error fetching edited message: {why:?}");
+                self.record_api_error(ApiOp::Fetch);
                 return;
             }
-            if msg.content.len() > self.message_length_max {
-                self.record_skip(SkipReason::TooLong);
+        };
+
+        if msg.author.bot {
+            return;
+        }
+
+        if !self.should_summarize(&msg) {
+            self.record_skip(SkipReason::ChannelNotPermitted);
+            return;
+        }
+
+        if self.summarize_mode == SummarizeMode::Auto
+            && msg.guild_id.is_some()
+            && msg.content.len() < self.message_length_min
+        {
+            self.record_skip(SkipReason::TooShort);
+            return;
+        }
+
+        if msg.content.trim().is_empty() && !self.has_images(&msg) {
+            self.record_skip(SkipReason::Empty);
+            return;
+        }
+
+        let Some(_in_flight) = self.begin_summary(msg.id) else {
+            self.record_skip(SkipReason::InFlight);
+            return;
+        };
+        self.summarize(&ctx, &msg, msg.channel_id, previous).await;
+    }
+
+    async fn reaction_add(&self, ctx: serenity::client::Context, reaction: Reaction) {
+        if self.summarize_mode != SummarizeMode::Reaction
+            || !reaction.emoji.unicode_eq(&self.summarize_emoji)
+        {
+            return;
+        }
+
+        // Ignore the bot's own reactions
+        if reaction.user_id == Some(ctx.cache.current_user().id) {
+            return;
+        }
+
+        let msg = match reaction.message(&ctx.http).await {
+            Ok(msg) => msg,
+            Err(why) => {
+                error!("Error fetching reacted message: {why:?}");
+                self.record_api_error(ApiOp::Fetch);
                 return;
             }
+        };
+
+        if msg.author.bot {
+            return;
+        }
+
+        if !self.should_summarize(&msg) {
+            self.record_skip(SkipReason::ChannelNotPermitted);
+            return;
+        }
+
+        if msg.content.trim().is_empty() && !self.has_images(&msg) {
+            self.record_skip(SkipReason::Empty);
+            return;
+        }
+
+        // Claim the message before creating a thread, so a second reaction
+        // doesn't start a second thread
+        let Some(_in_flight) = self.begin_summary(msg.id) else {
+            self.record_skip(SkipReason::InFlight);
+            return;
+        };
+
+        // Reply in a thread off the message to keep the channel tidy. DMs
+        // can't have threads, and creating one fails if the message already
+        // has a thread, so fall back to replying in the channel.
+        let channel_id = if msg.guild_id.is_some() {
+            match msg
+                .channel_id
+                .create_thread_from_message(
+                    &ctx.http,
+                    msg.id,
+                    CreateThread::new(format!(
+                        "Summary of {}'s message",
+                        msg.author.display_name()
+                    )),
+                )
+                .await
+            {
+                Ok(thread) => thread.id,
+                Err(why) => {
+                    warn!("Error creating summary thread: {why:?}");
+                    self.record_api_error(ApiOp::CreateThread);
+                    msg.channel_id
+                }
+            }
+        } else {
+            msg.channel_id
+        };
+
+        self.summarize(&ctx, &msg, channel_id, None).await;
+    }
+
+    async fn interaction_create(&self, ctx: serenity::client::Context, interaction: Interaction) {
+        if let Interaction::Command(command) = interaction
+            && command.data.name == commands::SUMMARIZE
+        {
+            self.summarize_command(&ctx, &command).await;
         }
+    }
+
+    async fn ready(&self, ctx: serenity::client::Context, ready: Ready) {
+        info!("{} is connected!", ready.user.name);
+
+        if let Err(why) = Command::create_global_command(&ctx.http, commands::summarize()).await {
+            error!("Error registering commands: {why:?}");
+        }
+    }
+}
+
+impl Handler {
+    pub fn new(
+        summary_generator: SummaryGenerator,
+        config: &Config,
+        metrics: Option<MetricsClient<Event>>,
+    ) -> Self {
+        Handler {
+            summary_generator,
+            message_length_min: config.message_length_min,
+            message_length_max: config.message_length_max,
+            chunk_window: config.chunk_window,
+            chunk_overlap: config.chunk_overlap,
+            summarize_channels: config.summarize_channels.clone(),
+            summarize_channels_deny: config.summarize_channels_deny.clone(),
+            llm_max_retries: config.llm_max_retries,
+            edit_throttle: config.edit_throttle,
+            summarize_mode: config.summarize_mode,
+            summarize_emoji: config.summarize_emoji.clone(),
+            summary_max_chars: config.summary_max_chars,
+            llm_image_max_bytes: config.llm_image_max_bytes,
+            in_flight: std::sync::Mutex::new(HashSet::new()),
+            summaries: Mutex::new(LruCache::new(SUMMARY_CACHE_SIZE)),
+            metrics,
+        }
+    }
+
+    /// Whether `msg` was posted in a channel the bot may summarize. DMs are
+    /// permitted unless explicitly denied.
+    fn should_summarize(&self, msg: &Message) -> bool {
+        if self.summarize_channels_deny.contains(&msg.channel_id) {
+            return false;
+        }
+
+        if msg.guild_id.is_none() {
+            return true;
+        }
+
+        self.summarize_channels
+            .as_ref()
+            .is_none_or(|allowed| allowed.contains(&msg.channel_id))
+    }
+
+    /// Marks a summary of `message_id` as in flight until the returned guard is
+    /// dropped. Returns `None` if one already is, in which case the caller
+    /// should not summarize it again.
+    fn begin_summary(&self, message_id: MessageId) -> Option<InFlight<'_>> {
+        let inserted = self
+            .in_flight
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(message_id);
+        // Only build the guard once the mark is ours: dropping one clears it
+        inserted.then(|| InFlight {
+            in_flight: &self.in_flight,
+            message_id,
+        })
+    }
+
+    /// Summarizes `msg`, posting the summary to `channel_id`. When `previous`
+    /// locates an earlier summary of `msg`, that summary is updated in place
+    /// instead.
+    async fn summarize(
+        &self,
+        ctx: &serenity::client::Context,
+        msg: &Message,
+        channel_id: ChannelId,
+        previous: Option<(ChannelId, MessageId)>,
+    ) {
+        let is_dm = msg.guild_id.is_none();
+        let source = if is_dm { Source::Dm } else { Source::Guild };
 
         if is_dm {
             info!(
@@ -70,30 +349,46 @@ impl EventHandler for Handler {
         let message_link = msg.link();
         let author_ref = msg.author.mention().to_string();
 
-        let mut response = match msg
-            .channel_id
-            .send_message(
-                &ctx.http,
-                CreateMessage::new().embed(CreateEmbed::new().description(format!(
-                    "### :hourglass: Summarizing [message]({message_link}) from {author_ref}"
-                ))),
-            )
-            .await
-        {
-            Ok(msg) => msg,
-            Err(why) => {
-                error!("Error sending initial message: {why:?}");
-                self.record_api_error(ApiOp::Send);
-                return;
-            }
+        let placeholder =
+            format!("### :hourglass: Summarizing [message]({message_link}) from {author_ref}");
+        let previous = match previous {
+            Some(previous) => self.reuse_summary(ctx, previous, placeholder.clone()).await,
+            None => None,
+        };
+        let mut response = match previous {
+            Some(response) => response,
+            None => match channel_id
+                .send_message(
+                    &ctx.http,
+                    CreateMessage::new().embed(CreateEmbed::new().description(placeholder)),
+                )
+                .await
+            {
+                Ok(msg) => msg,
+                Err(why) => {
+                    error!("Error sending initial message: {why:?}");
+                    self.record_api_error(ApiOp::Send);
+                    return;
+                }
+            },
+        };
+
+        // Stopped when dropped, so every way out of here clears it
+        let _typing = TypingIndicator::start(Arc::clone(&ctx.http), response.channel_id);
+
+        // Messages too long to summarize in one go are summarized in parts
+        let content = normalize_content(&ctx.cache, msg);
+        let parts = if content.chars().count() > self.message_length_max {
+            chunk_content(&content, self.chunk_window, self.chunk_overlap)
+        } else {
+            vec![content]
         };
 
         let input_len = msg.content.len();
         let author_id = msg.author.id.to_string();
         let started = Instant::now();
         let summary = self
-            .summary_generator
-            .generate_summary(msg.author.display_name(), &msg.content)
+            .stream_summary(ctx, &mut response, msg, &parts, &message_link, &author_ref)
             .await;
         let latency_ms = started.elapsed().as_millis() as f64;
 
@@ -107,26 +402,54 @@ impl EventHandler for Handler {
                     input_len,
                     Some(summary.len()),
                 );
-                summary
+                self.enforce_max_chars(summary)
             }
             Err(why) => {
                 error!("Error summarizing message: {why:?}");
-                let outcome = match why {
-                    SummaryError::Timeout => Outcome::Timeout,
-                    SummaryError::Generation(_) => Outcome::LlmError,
-                };
-                self.record_summary(source, &author_id, outcome, latency_ms, input_len, None);
+                self.record_summary(
+                    source,
+                    &author_id,
+                    outcome(&why),
+                    latency_ms,
+                    input_len,
+                    None,
+                );
 
-                if let Err(why) = response.delete(&ctx.http).await {
-                    error!("Error deleting initial message: {why:?}");
+                // Failures users can make sense of are reported in place;
+                // anything else just cleans up.
+                if let Some(reason) = failure_reason(&why) {
+                    let body = format!(
+                        "### :warning: {reason}\n\
+                         Couldn't summarize [message]({message_link}) from {author_ref}"
+                    );
+                    if let Err(why) = edit_description(ctx, &mut response, body).await {
+                        error!("Error reporting summary failure: {why:?}");
+                        self.record_api_error(ApiOp::Edit);
+                    }
+                    self.summaries
+                        .lock()
+                        .await
+                        .put(msg.id, (response.channel_id, response.id));
+                } else {
+                    if let Err(why) = response.delete(&ctx.http).await {
+                        error!("Error deleting initial message: {why:?}");
+                    }
+                    self.summaries.lock().await.pop(&msg.id);
                 }
 
                 return;
             }
         };
 
-        let body =
-            format!("### Summarized [message]({message_link}) from {author_ref}\n\n{summary}");
+        let body = if parts.len() > 1 {
+            format!(
+                "### Summarized [message]({message_link}) from {author_ref}\n\
+                 _(summarized from {} parts)_\n\n{summary}",
+                parts.len()
+            )
+        } else {
+            format!("### Summarized [message]({message_link}) from {author_ref}\n\n{summary}")
+        };
 
         if let Err(why) = response
             .edit(
@@ -138,24 +461,468 @@ impl EventHandler for Handler {
             error!("Error sending message: {why:?}");
             self.record_api_error(ApiOp::Edit);
         }
+
+        self.summaries
+            .lock()
+            .await
+            .put(msg.id, (response.channel_id, response.id));
     }
 
-    async fn ready(&self, _: serenity::client::Context, ready: Ready) {
-        info!("{} is connected!", ready.user.name);
+    /// Handles `/summarize`, replying with a summary only the invoker can see.
+    async fn summarize_command(
+        &self,
+        ctx: &serenity::client::Context,
+        command: &CommandInteraction,
+    ) {
+        if let Err(why) = command.defer_ephemeral(&ctx.http).await {
+            error!("Error acknowledging command: {why:?}");
+            self.record_api_error(ApiOp::Send);
+            return;
+        }
+
+        let body = match self.resolve_linked_message(ctx, command).await {
+            Ok(msg) => {
+                let author_id = msg.author.id.to_string();
+                let started = Instant::now();
+                let summary = self
+                    .summarize_quietly(&msg, &normalize_content(&ctx.cache, &msg))
+                    .await;
+                let latency_ms = started.elapsed().as_millis() as f64;
+
+                match summary {
+                    Ok(summary) => {
+                        self.record_summary(
+                            Source::Command,
+                            &author_id,
+                            Outcome::Success,
+                            latency_ms,
+                            msg.content.len(),
+                            Some(summary.len()),
+                        );
+                        format!(
+                            "### Summarized [message]({}) from {}\n\n{}",
+                            msg.link(),
+                            msg.author.mention(),
+                            self.enforce_max_chars(summary)
+                        )
+                    }
+                    Err(why) => {
+                        error!("Error summarizing linked message: {why:?}");
+                        self.record_summary(
+                            Source::Command,
+                            &author_id,
+                            outcome(&why),
+                            latency_ms,
+                            msg.content.len(),
+                            None,
+                        );
+                        match failure_reason(&why) {
+                            Some(reason) => format!(":x: {reason}."),
+                            None => {
+                                ":x: Couldn't summarize that message, try again later.".to_owned()
+                            }
+                        }
+                    }
+                }
+            }
+            Err(reason) => format!(":x: {reason}"),
+        };
+
+        if let Err(why) = command
+            .edit_response(
+                &ctx.http,
+                EditInteractionResponse::new().embed(CreateEmbed::new().description(body)),
+            )
+            .await
+        {
+            error!("Error responding to command: {why:?}");
+            self.record_api_error(ApiOp::Edit);
+        }
     }
-}
 
-impl Handler {
-    pub fn new(
-        summary_generator: SummaryGenerator,
-        config: &Config,
-        metrics: Option<MetricsClient<Event>>,
-    ) -> Self {
-        Handler {
-            summary_generator,
-            message_length_min: config.message_length_min,
-            message_length_max: config.message_length_max,
-            metrics,
+    /// Fetches the message linked in `command`. On failure, returns a reason
+    /// fit to show the invoker.
+    async fn resolve_linked_message(
+        &self,
+        ctx: &serenity::client::Context,
+        command: &CommandInteraction,
+    ) -> Result<Message, &'static str> {
+        const UNREADABLE: &str =
+            "I can't read that message. Check the link, and that we can both see its channel.";
+
+        let link = command
+            .data
+            .options
+            .iter()
+            .find(|option| option.name == commands::MESSAGE_LINK)
+            .and_then(|option| option.value.as_str())
+            .and_then(commands::parse_message_link)
+            .ok_or("That doesn't look like a message link.")?;
+
+        // Only summarize messages the invoker can see: those in this server,
+        // or in this DM.
+        match (link.guild_id, command.guild_id) {
+            (Some(linked), Some(current)) if linked == current => {
+                if !self
+                    .invoker_can_read(ctx, command, linked, link.channel_id)
+                    .await
+                {
+                    return Err(UNREADABLE);
+                }
+            }
+            (None, None) if link.channel_id == command.channel_id => {}
+            _ => return Err("I can only summarize messages from this conversation's server."),
+        }
+
+        let msg = match link.channel_id.message(&ctx.http, link.message_id).await {
+            Ok(msg) => msg,
+            Err(why) => {
+                warn!("Error fetching linked message: {why:?}");
+                self.record_api_error(ApiOp::Fetch);
+                return Err(UNREADABLE);
+            }
+        };
+
+        if !self.should_summarize(&msg) {
+            self.record_skip(SkipReason::ChannelNotPermitted);
+            return Err("Summaries are turned off in that channel.");
+        }
+
+        if msg.content.trim().is_empty() && !self.has_images(&msg) {
+            self.record_skip(SkipReason::Empty);
+            return Err("That message has no text to summarize.");
+        }
+
+        Ok(msg)
+    }
+
+    /// Whether the member who invoked `command` may read the message history
+    /// of `channel_id`. Threads are checked against their parent channel.
+    async fn invoker_can_read(
+        &self,
+        ctx: &serenity::client::Context,
+        command: &CommandInteraction,
+        guild_id: GuildId,
+        channel_id: ChannelId,
+    ) -> bool {
+        let Some(member) = command.member.as_deref() else {
+            return false;
+        };
+
+        let fetch_channel = async |channel_id: ChannelId| -> Option<GuildChannel> {
+            match channel_id.to_channel(&ctx.http).await {
+                Ok(Channel::Guild(channel)) => Some(channel),
+                Ok(_) => None,
+                Err(why) => {
+                    warn!("Error fetching linked channel: {why:?}");
+                    None
+                }
+            }
+        };
+        let Some(mut channel) = fetch_channel(channel_id).await else {
+            return false;
+        };
+        if channel.thread_metadata.is_some() {
+            let Some(parent) = channel.parent_id else {
+                return false;
+            };
+            let Some(parent) = fetch_channel(parent).await else {
+                return false;
+            };
+            channel = parent;
+        }
+
+        let guild = match guild_id.to_partial_guild(&ctx.http).await {
+            Ok(guild) => guild,
+            Err(why) => {
+                warn!("Error fetching guild: {why:?}");
+                return false;
+            }
+        };
+
+        guild
+            .user_permissions_in(&channel, member)
+            .contains(Permissions::VIEW_CHANNEL | Permissions::READ_MESSAGE_HISTORY)
+    }
+
+    /// Cuts `summary` down to the configured limit at a word boundary, for
+    /// when the model ignores its instruction to keep it short.
+    fn enforce_max_chars(&self, summary: String) -> String {
+        let len = summary.chars().count();
+        if len <= self.summary_max_chars {
+            return summary;
+        }
+        warn!(
+            "Summary of {len} characters is over the {} character limit, truncating",
+            self.summary_max_chars
+        );
+
+        // Leave room for the ellipsis
+        let end = summary
+            .char_indices()
+            .nth(self.summary_max_chars - 1)
+            .map_or(summary.len(), |(index, _)| index);
+        let cut = &summary[..end];
+        // Back up to the last word boundary, unless there isn't one
+        let cut = match cut.rfind(char::is_whitespace) {
+            Some(index) if index > 0 => &cut[..index],
+            _ => cut,
+        };
+        format!("{}…", cut.trim_end())
+    }
+
+    /// Whether `msg` has images the vision model can summarize.
+    fn has_images(&self, msg: &Message) -> bool {
+        self.summary_generator.has_vision() && msg.attachments.iter().any(is_image)
+    }
+
+    /// Downloads the images attached to `msg` for the vision model. Images
+    /// over the size limit, or that fail to download, are left out. Without a
+    /// vision model nothing is downloaded.
+    async fn load_images(&self, msg: &Message) -> Vec<Image> {
+        if !self.summary_generator.has_vision() {
+            return Vec::new();
+        }
+
+        let mut images = Vec::new();
+        for attachment in msg
+            .attachments
+            .iter()
+            .filter(|attachment| is_image(attachment))
+        {
+            if attachment.size > self.llm_image_max_bytes {
+                warn!(
+                    "Skipping image {} ({} bytes), over the {} byte limit",
+                    attachment.filename, attachment.size, self.llm_image_max_bytes
+                );
+                continue;
+            }
+
+            match attachment.download().await {
+                Ok(bytes) => images.push(Image::from_base64(BASE64_STANDARD.encode(bytes))),
+                Err(why) => {
+                    warn!("Error downloading image {}: {why:?}", attachment.filename);
+                    self.record_api_error(ApiOp::Fetch);
+                }
+            }
+        }
+        images
+    }
+
+    /// Summarizes `content`, the normalized content of `msg`, without posting
+    /// progress anywhere, splitting it into
+    /// parts when it is too long to summarize in one go.
+    async fn summarize_quietly(
+        &self,
+        msg: &Message,
+        content: &str,
+    ) -> Result<String, SummaryError> {
+        let author = msg.author.display_name();
+        let images = self.load_images(msg).await;
+        if content.chars().count() <= self.message_length_max {
+            let request = SummaryRequest::Message {
+                author,
+                content,
+                images: &images,
+            };
+            return self
+                .summary_generator
+                .generate_summary(request, self.summary_generator.reserve().await)
+                .await?
+                .collect()
+                .await;
+        }
+
+        let parts = chunk_content(content, self.chunk_window, self.chunk_overlap);
+        let mut summaries = Vec::with_capacity(parts.len());
+        for (index, part) in parts.iter().enumerate() {
+            // Images go with the first part, which they most likely relate to
+            let request = SummaryRequest::Message {
+                author,
+                content: part,
+                images: if index == 0 { &images } else { &[] },
+            };
+            let summary = self
+                .summary_generator
+                .generate_summary(request, self.summary_generator.reserve().await)
+                .await?
+                .collect()
+                .await?;
+            summaries.push(summary);
+        }
+
+        let request = SummaryRequest::Combine {
+            author,
+            summaries: &summaries,
+        };
+        self.summary_generator
+            .generate_summary(request, self.summary_generator.reserve().await)
+            .await?
+            .collect()
+            .await
+    }
+
+    /// Fetches the earlier summary at `previous` and resets it to `placeholder`
+    /// so it can be regenerated in place. Returns `None` if it can't be reused,
+    /// e.g. because it was deleted, in which case a new summary should be
+    /// posted.
+    async fn reuse_summary(
+        &self,
+        ctx: &serenity::client::Context,
+        (channel_id, message_id): (ChannelId, MessageId),
+        placeholder: String,
+    ) -> Option<Message> {
+        let mut response = match channel_id.message(&ctx.http, message_id).await {
+            Ok(response) => response,
+            Err(why) => {
+                warn!("Error fetching previous summary: {why:?}");
+                self.record_api_error(ApiOp::Fetch);
+                return None;
+            }
+        };
+
+        if let Err(why) = edit_description(ctx, &mut response, placeholder).await {
+            warn!("Error resetting previous summary: {why:?}");
+            self.record_api_error(ApiOp::Edit);
+            return None;
+        }
+
+        Some(response)
+    }
+
+    /// Generates a summary of `msg`, editing the partial text into `response`
+    /// as it streams in. Edits are throttled, so the caller must still make a
+    /// final edit with the complete summary.
+    ///
+    /// `parts` holds the content to summarize. When there is more than one
+    /// part, each is summarized in turn and the final summary combines theirs.
+    async fn stream_summary(
+        &self,
+        ctx: &serenity::client::Context,
+        response: &mut Message,
+        msg: &Message,
+        parts: &[String],
+        message_link: &str,
+        author_ref: &str,
+    ) -> Result<String, SummaryError> {
+        let author = msg.author.display_name();
+        let images = self.load_images(msg).await;
+        let mut part_summaries = Vec::with_capacity(parts.len());
+        if parts.len() > 1 {
+            for (index, part) in parts.iter().enumerate() {
+                let body = format!(
+                    "### :hourglass: Summarizing part {}/{} of [message]({message_link}) from \
+                     {author_ref}",
+                    index + 1,
+                    parts.len()
+                );
+                if let Err(why) = edit_description(ctx, response, body).await {
+                    warn!("Error updating summary progress: {why:?}");
+                }
+
+                // Images go with the first part, which they most likely relate
+                // to
+                let request = SummaryRequest::Message {
+                    author,
+                    content: part,
+                    images: if index == 0 { &images } else { &[] },
+                };
+                let stream = self
+                    .generate_with_retry(ctx, response, request, message_link, author_ref)
+                    .await?;
+                part_summaries.push(stream.collect().await?);
+            }
+        }
+
+        let request = if part_summaries.is_empty() {
+            SummaryRequest::Message {
+                author,
+                content: &parts[0],
+                images: &images,
+            }
+        } else {
+            SummaryRequest::Combine {
+                author,
+                summaries: &part_summaries,
+            }
+        };
+        let mut stream = self
+            .generate_with_retry(ctx, response, request, message_link, author_ref)
+            .await?;
+        let mut last_edit = Instant::now();
+
+        while let Some(partial) = stream.next().await {
+            let partial = partial?;
+            if last_edit.elapsed() < self.edit_throttle {
+                continue;
+            }
+            last_edit = Instant::now();
+
+            let header = format!(
+                "### :hourglass: Summarizing [message]({message_link}) from {author_ref}\n\n"
+            );
+            let limit = DISCORD_MESSAGE_LIMIT.saturating_sub(header.chars().count());
+            let body = format!("{header}{}", truncate_preview(partial, limit));
+            if let Err(why) = edit_description(ctx, response, body).await {
+                // The final edit still lands the full summary, so a dropped
+                // preview isn't worth more than a warning.
+                warn!("Error updating streamed summary: {why:?}");
+            }
+        }
+
+        Ok(stream.into_summary())
+    }
+
+    /// Starts generating a summary, retrying with exponential backoff while the
+    /// LLM backend can't be reached. `response` is updated between attempts so
+    /// users can see the bot hasn't given up.
+    async fn generate_with_retry(
+        &self,
+        ctx: &serenity::client::Context,
+        response: &mut Message,
+        request: SummaryRequest<'_>,
+        message_link: &str,
+        author_ref: &str,
+    ) -> Result<SummaryStream, SummaryError> {
+        let mut retry = Retry::new(self.llm_max_retries);
+        loop {
+            let permit = match self.summary_generator.try_reserve() {
+                Some(permit) => permit,
+                None => {
+                    info!(
+                        "Summary queued, {} already waiting",
+                        self.summary_generator.queue_depth()
+                    );
+                    let body = format!(
+                        "### :hourglass: Queued…\n\
+                         Summarizing [message]({message_link}) from {author_ref}"
+                    );
+                    if let Err(why) = edit_description(ctx, response, body).await {
+                        warn!("Error updating queued status: {why:?}");
+                    }
+                    self.summary_generator.reserve().await
+                }
+            };
+
+            let why = match self
+                .summary_generator
+                .generate_summary(request, permit)
+                .await
+            {
+                Ok(stream) => return Ok(stream),
+                Err(why) => why,
+            };
+
+            let attempt = retry.wait(why).await?;
+
+            let body = format!(
+                "### :hourglass: Retrying ({attempt}/{max})…\n\
+                 Summarizing [message]({message_link}) from {author_ref}",
+                max = self.llm_max_retries
+            );
+            if let Err(why) = edit_description(ctx, response, body).await {
+                warn!("Error updating retry status: {why:?}");
+            }
         }
     }
 
@@ -205,3 +972,258 @@ impl Handler {
         }
     }
 }
+
+/// Rewrites Discord markup in `msg`'s content that would confuse the model:
+/// mentions become the names they display as, and custom emoji become
+/// `:name:`.
+fn normalize_content(cache: &Cache, msg: &Message) -> String {
+    normalize_markup(&msg.content, |mention| mention_name(cache, msg, mention))
+}
+
+/// Rewrites the markup in `content` as plain text, naming mentions with
+/// `name`.
+fn normalize_markup(content: &str, name: impl Fn(Mention) -> Option<String>) -> String {
+    let mut normalized = String::with_capacity(content.len());
+    let mut rest = content;
+    while let Some(start) = rest.find('<') {
+        normalized.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find('>') else {
+            break;
+        };
+
+        match markup_text(&rest[..=end], &name) {
+            Some(replacement) => {
+                normalized.push_str(&replacement);
+                rest = &rest[end + 1..];
+            }
+            // Not markup, e.g. a literal `<`; keep it and carry on after it
+            None => {
+                normalized.push('<');
+                rest = &rest[1..];
+            }
+        }
+    }
+    normalized.push_str(rest);
+    normalized
+}
+
+/// The plain-text form of a single piece of markup like `<@123>`, or `None`
+/// if `markup` isn't markup. Mentions `name` can't name fall back to a generic
+/// name.
+fn markup_text(markup: &str, name: impl Fn(Mention) -> Option<String>) -> Option<String> {
+    // Custom emoji: `<:name:id>`, or `<a:name:id>` when animated
+    let inner = markup.strip_prefix('<')?.strip_suffix('>')?;
+    if let Some(emoji) = inner.strip_prefix("a:").or_else(|| inner.strip_prefix(':')) {
+        let (emoji_name, id) = emoji.split_once(':')?;
+        id.parse::<u64>().ok()?;
+        return Some(format!(":{emoji_name}:"));
+    }
+
+    let mention = markup.parse::<Mention>().ok()?;
+    let (prefix, fallback) = match mention {
+        Mention::User(_) => ('@', "someone"),
+        Mention::Role(_) => ('@', "role"),
+        Mention::Channel(_) => ('#', "channel"),
+    };
+    let name = name(mention);
+    Some(format!("{prefix}{}", name.as_deref().unwrap_or(fallback)))
+}
+
+/// The name `mention` displays as in `msg`, if `msg` or the cache knows it.
+fn mention_name(cache: &Cache, msg: &Message, mention: Mention) -> Option<String> {
+    match mention {
+        Mention::User(id) => {
+            let user = msg.mentions.iter().find(|user| user.id == id)?;
+            Some(
+                user.member
+                    .as_ref()
+                    .and_then(|member| member.nick.as_deref())
+                    .unwrap_or(user.display_name())
+                    .to_owned(),
+            )
+        }
+        Mention::Role(id) => {
+            let guild = cache.guild(msg.guild_id?)?;
+            guild.roles.get(&id).map(|role| role.name.clone())
+        }
+        Mention::Channel(id) => {
+            let guild = cache.guild(msg.guild_id?)?;
+            guild.channels.get(&id).map(|channel| channel.name.clone())
+        }
+    }
+}
+
+/// Keeps a message marked as having a summary in flight until dropped, so
+/// every way out of summarizing it, panics included, clears the mark.
+struct InFlight<'a> {
+    in_flight: &'a std::sync::Mutex<HashSet<MessageId>>,
+    message_id: MessageId,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.in_flight
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.message_id);
+    }
+}
+
+/// Keeps the typing indicator showing in a channel until dropped.
+struct TypingIndicator(JoinHandle<()>);
+
+impl TypingIndicator {
+    fn start(http: Arc<Http>, channel_id: ChannelId) -> Self {
+        Self(tokio::spawn(async move {
+            let mut ticks = interval(TYPING_INTERVAL);
+            loop {
+                ticks.tick().await;
+                if let Err(why) = channel_id.broadcast_typing(&http).await {
+                    warn!("Error showing typing indicator: {why:?}");
+                }
+            }
+        }))
+    }
+}
+
+impl Drop for TypingIndicator {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// The metric outcome of a failed summary.
+fn outcome(why: &SummaryError) -> Outcome {
+    match why {
+        SummaryError::Timeout => Outcome::Timeout,
+        SummaryError::ModelUnavailable(_) => Outcome::ModelUnavailable,
+        SummaryError::Connection(_) => Outcome::Unreachable,
+        SummaryError::Other(_) => Outcome::LlmError,
+    }
+}
+
+/// Why a summary failed, in terms fit to show users. `None` for failures with
+/// nothing useful to say.
+fn failure_reason(why: &SummaryError) -> Option<&'static str> {
+    match why {
+        SummaryError::Timeout => Some("Summary timed out"),
+        SummaryError::ModelUnavailable(_) => Some("The summary model isn't available"),
+        SummaryError::Connection(_) => Some("The summarizer can't be reached right now"),
+        SummaryError::Other(_) => None,
+    }
+}
+
+/// Whether `attachment` is an image, going by its content type.
+fn is_image(attachment: &Attachment) -> bool {
+    attachment
+        .content_type
+        .as_deref()
+        .is_some_and(|content_type| content_type.starts_with("image/"))
+}
+
+/// Replaces the description of `response`'s embed with `body`.
+async fn edit_description(
+    ctx: &serenity::client::Context,
+    response: &mut Message,
+    body: String,
+) -> serenity::Result<()> {
+    response
+        .edit(
+            &ctx.http,
+            EditMessage::new().embed(CreateEmbed::new().description(body)),
+        )
+        .await
+}
+
+/// Truncates a partially-streamed summary to at most `limit` chars, so it fits
+/// in a Discord message after its header, marking the cut with an ellipsis.
+fn truncate_preview(text: &str, limit: usize) -> Cow<'_, str> {
+    if text.chars().count() <= limit {
+        return Cow::Borrowed(text);
+    }
+
+    // Leave room for the ellipsis
+    let end = text
+        .char_indices()
+        .nth(limit.saturating_sub(1))
+        .map_or(text.len(), |(index, _)| index);
+    Cow::Owned(format!("{}…", &text[..end]))
+}
+
+#[cfg(test)]
+mod tests {
+    use serenity::all::{RoleId, UserId};
+
+    use super::*;
+
+    /// Names user 1, role 2 and channel 3; everything else is unknown.
+    fn name(mention: Mention) -> Option<String> {
+        match mention {
+            Mention::User(id) if id == UserId::new(1) => Some("alice".to_owned()),
+            Mention::Role(id) if id == RoleId::new(2) => Some("mods".to_owned()),
+            Mention::Channel(id) if id == ChannelId::new(3) => Some("general".to_owned()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn begin_summary_rejects_a_message_already_in_flight() {
+        let config = crate::config::test_config();
+        let handler = Handler::new(SummaryGenerator::new(&config), &config, None);
+        let id = MessageId::new(1);
+
+        let guard = handler
+            .begin_summary(id)
+            .expect("first summary should begin");
+        assert!(handler.begin_summary(id).is_none());
+        assert!(handler.begin_summary(MessageId::new(2)).is_some());
+
+        drop(guard);
+        assert!(handler.begin_summary(id).is_some());
+    }
+
+    #[test]
+    fn names_user_mentions() {
+        assert_eq!(normalize_markup("hi <@1>", name), "hi @alice");
+        assert_eq!(normalize_markup("hi <@!1>", name), "hi @alice");
+    }
+
+    #[test]
+    fn names_role_mentions() {
+        assert_eq!(
+            normalize_markup("<@&2> please look", name),
+            "@mods please look"
+        );
+    }
+
+    #[test]
+    fn names_channel_mentions() {
+        assert_eq!(normalize_markup("see <#3>", name), "see #general");
+    }
+
+    #[test]
+    fn unknown_ids_fall_back_to_generic_names() {
+        assert_eq!(
+            normalize_markup("<@9> <@&9> <#9>", name),
+            "@someone @role #channel"
+        );
+    }
+
+    #[test]
+    fn custom_emoji_become_their_names() {
+        assert_eq!(
+            normalize_markup("nice <:wave:123> <a:dance:456>", name),
+            "nice :wave: :dance:"
+        );
+    }
+
+    #[test]
+    fn keeps_text_that_isnt_markup() {
+        assert_eq!(
+            normalize_markup("1 < 2 and <not markup> <@x>", name),
+            "1 < 2 and <not markup> <@x>"
+        );
+        assert_eq!(normalize_markup("a <b", name), "a <b");
+    }
+}
@@ -7,36 +7,52 @@ use crate::config::Config;
 use crate::handler::Handler;
 use crate::llm::SummaryGenerator;
 
+mod commands;
 mod config;
 mod handler;
 mod llm;
 mod metrics;
-
-/// Service identifier reported with every metric and heartbeat.
-const METRICS_SOURCE: &str = "summarizer-bot";
+mod prompt;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     shared::init_tracing!()?;
     let config = Config::from_env()?;
 
-    let intents = GatewayIntents::GUILD_MESSAGES
+    // GUILDS fills the cache with the role and channel names that mentions
+    // are replaced with before summarizing.
+    let intents = GatewayIntents::GUILDS
+        | GatewayIntents::GUILD_MESSAGES
         | GatewayIntents::MESSAGE_CONTENT
-        | GatewayIntents::DIRECT_MESSAGES;
+        | GatewayIntents::DIRECT_MESSAGES
+        | GatewayIntents::GUILD_MESSAGE_REACTIONS
+        | GatewayIntents::DIRECT_MESSAGE_REACTIONS;
 
     let metrics = config.metrics.as_ref().map(|metrics| {
-        info!("Metrics enabled, reporting to {}", metrics.ingest_endpoint);
+        info!(
+            "Metrics enabled, reporting to {} as {}",
+            metrics.ingest_endpoint, metrics.source
+        );
         MetricsClient::<metrics::Event>::new(
             ClientConfig::new(
                 &metrics.ingest_endpoint,
                 &metrics.heartbeat_endpoint,
-                METRICS_SOURCE,
+                &metrics.source,
             )
             .with_heartbeat_interval(metrics.heartbeat_interval),
         )
     });
 
     let summary_generator = SummaryGenerator::new(&config);
+    // Held for the life of the bot; dropping it stops watching the prompt.
+    let _prompt_watcher = prompt::watch_system_prompt(
+        config.system_prompt_path.clone(),
+        summary_generator.system_prompt(),
+    )?;
+    info!(
+        "Watching {} for system prompt changes",
+        config.system_prompt_path.display()
+    );
     let handler = Handler::new(summary_generator, &config, metrics.clone());
 
     let mut client = Client::builder(&config.bot.discord_token, intents)
@@ -48,6 +48,8 @@ pub mod value {
 pub enum Source {
     Dm,
     Guild,
+    /// Linked from the `/summarize` command.
+    Command,
 }
 
 impl Source {
@@ -55,6 +57,7 @@ impl Source {
         match self {
             Source::Dm => "dm",
             Source::Guild => "guild",
+            Source::Command => "command",
         }
     }
 }
@@ -64,6 +67,8 @@ impl Source {
 pub enum Outcome {
     Success,
     Timeout,
+    ModelUnavailable,
+    Unreachable,
     LlmError,
 }
 
@@ -72,6 +77,8 @@ impl Outcome {
         match self {
             Outcome::Success => "success",
             Outcome::Timeout => "timeout",
+            Outcome::ModelUnavailable => "model_unavailable",
+            Outcome::Unreachable => "unreachable",
             Outcome::LlmError => "llm_error",
         }
     }
@@ -81,14 +88,18 @@ impl Outcome {
 #[derive(Debug, Clone, Copy)]
 pub enum SkipReason {
     TooShort,
-    TooLong,
+    Empty,
+    ChannelNotPermitted,
+    InFlight,
 }
 
 impl SkipReason {
     pub fn as_str(self) -> &'static str {
         match self {
             SkipReason::TooShort => "too_short",
-            SkipReason::TooLong => "too_long",
+            SkipReason::Empty => "empty",
+            SkipReason::ChannelNotPermitted => "channel_not_permitted",
+            SkipReason::InFlight => "in_flight",
         }
     }
 }
@@ -98,6 +109,8 @@ impl SkipReason {
 pub enum ApiOp {
     Send,
     Edit,
+    Fetch,
+    CreateThread,
 }
 
 impl ApiOp {
@@ -105,6 +118,8 @@ impl ApiOp {
         match self {
             ApiOp::Send => "send",
             ApiOp::Edit => "edit",
+            ApiOp::Fetch => "fetch",
+            ApiOp::CreateThread => "create_thread",
         }
     }
 }
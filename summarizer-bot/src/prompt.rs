@@ -0,0 +1,86 @@
+//! Hot-reloading of the system prompt.
+//!
+//! The prompt file is watched for changes so edits take effect without a
+//! restart. The current prompt lives behind an [`ArcSwap`] so the summarizer
+//! can read it on every request without taking a lock.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow};
+use arc_swap::ArcSwap;
+use notify_debouncer_mini::notify::{RecommendedWatcher, RecursiveMode};
+use notify_debouncer_mini::{DebounceEventResult, Debouncer, new_debouncer};
+use tracing::{error, info};
+
+/// How long the prompt file must be quiet before an edit is picked up. Editors
+/// often write a file in several steps; this collapses them into one reload.
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Reads the system prompt from `path`, rejecting an empty prompt.
+pub fn read_system_prompt(path: &Path) -> Result<String> {
+    let prompt = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read system prompt from {}", path.display()))?;
+
+    if prompt.trim().is_empty() {
+        return Err(anyhow!("System prompt at {} is empty", path.display()));
+    }
+
+    Ok(prompt)
+}
+
+/// Watches the prompt file at `path`, swapping each edit into `prompt`. An edit
+/// that can't be read (or leaves the file empty) is logged and ignored, so the
+/// previous prompt stays in use.
+///
+/// Watching stops when the returned debouncer is dropped.
+pub fn watch_system_prompt(
+    path: PathBuf,
+    prompt: Arc<ArcSwap<String>>,
+) -> Result<Debouncer<RecommendedWatcher>> {
+    // Watch the parent directory rather than the file itself: editors commonly
+    // save by writing a new file and renaming it over the old one, which would
+    // orphan a watch on the original file.
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let file_name = path
+        .file_name()
+        .context("System prompt path must name a file")?
+        .to_owned();
+
+    let mut debouncer = new_debouncer(RELOAD_DEBOUNCE, move |result: DebounceEventResult| {
+        let events = match result {
+            Ok(events) => events,
+            Err(why) => {
+                error!("Error watching system prompt: {why:?}");
+                return;
+            }
+        };
+
+        if !events
+            .iter()
+            .any(|event| event.path.file_name() == Some(file_name.as_os_str()))
+        {
+            return;
+        }
+
+        match read_system_prompt(&path) {
+            Ok(new_prompt) => {
+                prompt.store(Arc::new(new_prompt));
+                info!("Reloaded system prompt from {}", path.display());
+            }
+            Err(why) => error!("Keeping previous system prompt: {why:?}"),
+        }
+    })
+    .context("Failed to create system prompt watcher")?;
+
+    debouncer
+        .watcher()
+        .watch(&dir, RecursiveMode::NonRecursive)
+        .with_context(|| format!("Failed to watch {}", dir.display()))?;
+
+    Ok(debouncer)
+}